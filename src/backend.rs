@@ -0,0 +1,245 @@
+use crate::buf::{init_slice, spare, zeroed};
+use crate::{BackendKind, FileImpl, IoBuf, IoBufMut};
+use std::future::Future;
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::time::SystemTime;
+
+pub trait FileIo: Send + Sync {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    fn write_at(&self, pos: u64, buf: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    fn sync_all(&self) -> impl Future<Output = Result<()>> + Send;
+
+    fn sync_data(&self) -> impl Future<Output = Result<()>> + Send;
+
+    fn set_len(&self, size: u64) -> impl Future<Output = Result<()>> + Send;
+
+    fn metadata(&self) -> impl Future<Output = Result<Metadata>> + Send;
+
+    fn read_uninit_at(
+        &self,
+        pos: u64,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> impl Future<Output = Result<usize>> + Send {
+        self.read_at(pos, zeroed(buf))
+    }
+
+    // Reads into the spare capacity of `buf`. Backends that can fill uninitialized memory, or
+    // that may keep using the buffer after the future is dropped, override this.
+    fn read_owned_at<T: IoBufMut>(
+        &self,
+        pos: u64,
+        mut buf: T,
+    ) -> impl Future<Output = (Result<usize>, T)> + Send {
+        async move {
+            let res = self.read_at(pos, zeroed(spare(&mut buf))).await;
+            if let Ok(cnt) = res {
+                unsafe { buf.set_init(buf.bytes_init() + cnt) };
+            }
+            (res, buf)
+        }
+    }
+
+    fn write_owned_at<T: IoBuf>(
+        &self,
+        pos: u64,
+        buf: T,
+    ) -> impl Future<Output = (Result<usize>, T)> + Send {
+        async move {
+            let res = self.write_at(pos, init_slice(&buf)).await;
+            (res, buf)
+        }
+    }
+
+    fn kind(&self) -> BackendKind {
+        BackendKind::Custom
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    len: u64,
+    readonly: bool,
+    modified: Option<SystemTime>,
+    accessed: Option<SystemTime>,
+    created: Option<SystemTime>,
+    std: Option<std::fs::Metadata>,
+}
+
+impl Metadata {
+    pub fn new(len: u64) -> Self {
+        Self {
+            len,
+            readonly: false,
+            modified: None,
+            accessed: None,
+            created: None,
+            std: None,
+        }
+    }
+
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    pub fn with_modified(mut self, time: SystemTime) -> Self {
+        self.modified = Some(time);
+        self
+    }
+
+    pub fn with_accessed(mut self, time: SystemTime) -> Self {
+        self.accessed = Some(time);
+        self
+    }
+
+    pub fn with_created(mut self, time: SystemTime) -> Self {
+        self.created = Some(time);
+        self
+    }
+
+    pub(crate) fn with_len(mut self, len: u64) -> Self {
+        self.len = len;
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn modified(&self) -> Result<SystemTime> {
+        time_or_unsupported(self.modified)
+    }
+
+    pub fn accessed(&self) -> Result<SystemTime> {
+        time_or_unsupported(self.accessed)
+    }
+
+    pub fn created(&self) -> Result<SystemTime> {
+        time_or_unsupported(self.created)
+    }
+
+    pub fn as_std(&self) -> Option<&std::fs::Metadata> {
+        self.std.as_ref()
+    }
+}
+
+fn time_or_unsupported(time: Option<SystemTime>) -> Result<SystemTime> {
+    time.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "timestamp not available from this backend",
+        )
+    })
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(meta: std::fs::Metadata) -> Self {
+        Self {
+            len: meta.len(),
+            readonly: meta.permissions().readonly(),
+            modified: meta.modified().ok(),
+            accessed: meta.accessed().ok(),
+            created: meta.created().ok(),
+            std: Some(meta),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Native(pub(crate) FileImpl);
+
+impl FileIo for Native {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.0.read_at(pos, buf).await
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        self.0.write_at(pos, buf).await
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.0.sync_all().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.0.sync_data().await
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.0.set_len(size).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        Ok(self.0.metadata().await?.into())
+    }
+
+    #[cfg(all(
+        unix,
+        not(any(
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))
+    ))]
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        self.0.read_owned_at(pos, buf).await
+    }
+
+    #[cfg(all(
+        unix,
+        not(any(
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))
+    ))]
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        self.0.read_uninit_at(pos, buf).await
+    }
+
+    #[cfg(all(
+        unix,
+        not(any(
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        ))
+    ))]
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        self.0.write_owned_at(pos, buf).await
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.0.backend()
+    }
+
+    #[cfg(unix)]
+    fn descriptor(&self) -> Option<i64> {
+        use std::os::fd::AsRawFd;
+        Some(self.0.as_raw_fd() as i64)
+    }
+
+    #[cfg(windows)]
+    fn descriptor(&self) -> Option<i64> {
+        use std::os::windows::io::AsRawHandle;
+        Some(self.0.as_raw_handle() as i64)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::BackendKind;
 use std::io::Result;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::task::Poll;
@@ -105,6 +106,22 @@ impl File {
     pub async fn set_len(&self, size: u64) -> Result<()> {
         self.0.set_len(size).await
     }
+
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        self.0.set_permissions(perm).await
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        Ok(Self(self.0.try_clone().await?))
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        BackendKind::Aio
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        self.0
+    }
 }
 
 impl From<tokio::fs::File> for File {
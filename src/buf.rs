@@ -0,0 +1,180 @@
+// Buffers that are handed over to an operation for as long as it runs. The thread pool keeps
+// using the memory after a cancelled future is dropped, so borrowed slices are not enough there.
+
+use std::mem::MaybeUninit;
+
+/// # Safety
+///
+/// The pointer must stay valid, and must not move, for as long as the buffer is alive, even when
+/// the buffer itself is moved.
+pub unsafe trait IoBuf: Send + 'static {
+    fn stable_ptr(&self) -> *const u8;
+
+    fn bytes_init(&self) -> usize;
+}
+
+/// # Safety
+///
+/// `stable_mut_ptr` must point to `bytes_total` bytes of writable memory, and `set_init` must make
+/// the first `len` of them part of the buffer.
+pub unsafe trait IoBufMut: IoBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8;
+
+    fn bytes_total(&self) -> usize;
+
+    /// # Safety
+    ///
+    /// The first `len` bytes must have been initialized.
+    unsafe fn set_init(&mut self, len: usize);
+}
+
+pub(crate) fn init_slice<T: IoBuf>(buf: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) }
+}
+
+pub(crate) fn spare<T: IoBufMut>(buf: &mut T) -> &mut [MaybeUninit<u8>] {
+    let (init, total) = (buf.bytes_init(), buf.bytes_total());
+    unsafe {
+        let spare = buf.stable_mut_ptr().add(init);
+        std::slice::from_raw_parts_mut(spare.cast(), total - init)
+    }
+}
+
+// For backends that can only read into initialized memory.
+pub(crate) fn zeroed(buf: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    buf.fill(MaybeUninit::new(0));
+    unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) }
+}
+
+pub(crate) fn copy_to_uninit(dst: &mut [MaybeUninit<u8>], src: &[u8]) {
+    for (dst, src) in dst.iter_mut().zip(src) {
+        dst.write(*src);
+    }
+}
+
+// Caps how much of the spare capacity a read may fill, for buffers that have more room than the
+// caller asked for.
+pub(crate) struct Limit<T> {
+    buf: T,
+    total: usize,
+}
+
+impl<T: IoBuf> Limit<T> {
+    pub(crate) fn new(buf: T, len: usize) -> Self {
+        let total = buf.bytes_init() + len;
+        Self { buf, total }
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.buf
+    }
+}
+
+unsafe impl<T: IoBuf> IoBuf for Limit<T> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.buf.stable_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.buf.bytes_init()
+    }
+}
+
+unsafe impl<T: IoBufMut> IoBufMut for Limit<T> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.stable_mut_ptr()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.buf.bytes_total().min(self.total)
+    }
+
+    unsafe fn set_init(&mut self, len: usize) {
+        self.buf.set_init(len)
+    }
+}
+
+unsafe impl IoBuf for Vec<u8> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+}
+
+unsafe impl IoBufMut for Vec<u8> {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+
+    unsafe fn set_init(&mut self, len: usize) {
+        if len > self.len() {
+            self.set_len(len);
+        }
+    }
+}
+
+unsafe impl IoBuf for Box<[u8]> {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+}
+
+unsafe impl IoBuf for &'static [u8] {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBuf for bytes::Bytes {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBuf for bytes::BytesMut {
+    fn stable_ptr(&self) -> *const u8 {
+        self.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+unsafe impl IoBufMut for bytes::BytesMut {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity()
+    }
+
+    unsafe fn set_init(&mut self, len: usize) {
+        if len > self.len() {
+            self.set_len(len);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use crate::buf::{copy_to_uninit, init_slice, spare};
+use crate::{BackendKind, File, FileIo, IoBuf, IoBufMut, Metadata, Native};
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+type Extents = BTreeMap<u64, Vec<u8>>;
+
+// Keeps extents disjoint and non-adjacent; newer data wins where it overlaps older data. Returns
+// by how many bytes the buffered total grew.
+fn insert(extents: &mut Extents, pos: u64, data: &[u8]) -> usize {
+    if data.is_empty() {
+        return 0;
+    }
+    let end = pos + data.len() as u64;
+    let touching: Vec<u64> = extents
+        .range(..=end)
+        .rev()
+        .take_while(|(&at, buf)| at + buf.len() as u64 >= pos)
+        .map(|(&at, _)| at)
+        .collect();
+    let mut old: Vec<(u64, Vec<u8>)> = touching
+        .into_iter()
+        .map(|at| (at, extents.remove(&at).expect("extent just seen")))
+        .collect();
+    let old_len: usize = old.iter().map(|(_, buf)| buf.len()).sum();
+    // Appending to the extent that starts first reuses its allocation, so a run of small
+    // adjacent writes stays linear.
+    let (start, mut merged) = match old.pop() {
+        Some((at, buf)) if at <= pos => (at, buf),
+        Some(other) => {
+            old.push(other);
+            (pos, Vec::new())
+        }
+        None => (pos, Vec::new()),
+    };
+    let merged_end = old
+        .iter()
+        .map(|(at, buf)| at + buf.len() as u64)
+        .fold(end.max(start + merged.len() as u64), u64::max);
+    merged.resize((merged_end - start) as usize, 0);
+    for (at, buf) in old {
+        let off = (at - start) as usize;
+        merged[off..off + buf.len()].copy_from_slice(&buf);
+    }
+    let off = (pos - start) as usize;
+    merged[off..off + data.len()].copy_from_slice(data);
+    let grown = merged.len() - old_len;
+    extents.insert(start, merged);
+    grown
+}
+
+fn overlay(extents: &Extents, pos: u64, buf: &mut [u8]) {
+    let end = pos + buf.len() as u64;
+    let first = extents.range(..=pos).next_back().map_or(pos, |(&at, _)| at);
+    for (&at, data) in extents.range(first..end) {
+        let from = at.max(pos);
+        let to = (at + data.len() as u64).min(end);
+        if from < to {
+            buf[(from - pos) as usize..(to - pos) as usize]
+                .copy_from_slice(&data[(from - at) as usize..(to - at) as usize]);
+        }
+    }
+}
+
+fn extents_end(extents: &Extents) -> u64 {
+    extents
+        .last_key_value()
+        .map_or(0, |(&at, data)| at + data.len() as u64)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    dirty: Extents,
+    dirty_bytes: usize,
+    // Extents handed to the backend by an in-progress flush. Reads still see them so that a
+    // flush never opens a window where freshly written data appears to be missing.
+    flushing: Extents,
+    // Bumped whenever `flushing` is cleared, so a read can tell that the backend changed under it.
+    epoch: u64,
+    timer_armed: bool,
+}
+
+#[derive(Debug)]
+struct Shared<B> {
+    inner: B,
+    capacity: usize,
+    max_delay: Option<Duration>,
+    state: Mutex<State>,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+// Data that was never flushed is lost when the writer is dropped.
+#[derive(Debug)]
+pub struct BufferedWriter<B = Native>(Arc<Shared<B>>);
+
+impl<B: FileIo + 'static> BufferedWriter<B> {
+    pub fn new(file: File<B>, capacity: usize) -> Self {
+        Self::build(file, capacity, None)
+    }
+
+    pub fn with_max_delay(file: File<B>, capacity: usize, max_delay: Duration) -> Self {
+        Self::build(file, capacity, Some(max_delay))
+    }
+
+    fn build(file: File<B>, capacity: usize, max_delay: Option<Duration>) -> Self {
+        Self(Arc::new(Shared {
+            inner: file.into_inner(),
+            capacity,
+            max_delay,
+            state: Mutex::new(State::default()),
+            flush_lock: tokio::sync::Mutex::new(()),
+        }))
+    }
+
+    pub fn into_file(self) -> File<Self> {
+        File::from_io(self)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.0.inner
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.0.state().dirty_bytes
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.flush_task().await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.flush_task().await?;
+        self.0.inner.sync_data().await
+    }
+
+    // Lays the buffered extents over `buf`, of which the first `filled` bytes came from the
+    // backend. Returns `None` if a flush finished since `epoch`, in which case the read has to
+    // be retried.
+    fn overlay_buffered(
+        &self,
+        epoch: u64,
+        pos: u64,
+        buf: &mut [u8],
+        filled: usize,
+    ) -> Option<usize> {
+        let state = self.0.state();
+        if state.epoch != epoch {
+            return None;
+        }
+        buf[filled..].fill(0);
+        overlay(&state.flushing, pos, buf);
+        overlay(&state.dirty, pos, buf);
+        let end = extents_end(&state.dirty).max(extents_end(&state.flushing));
+        let buffered = end.saturating_sub(pos).min(buf.len() as u64) as usize;
+        Some(filled.max(buffered))
+    }
+
+    // Like `read_at`, but the backend only ever fills a buffer owned by this function.
+    async fn read_owned(&self, pos: u64, len: usize) -> Result<Vec<u8>> {
+        loop {
+            let epoch = self.0.state().epoch;
+            let mut data = Vec::with_capacity(len);
+            while data.len() < len {
+                let (res, buf) = self
+                    .0
+                    .inner
+                    .read_owned_at(pos + data.len() as u64, data)
+                    .await;
+                data = buf;
+                if res? == 0 {
+                    break;
+                }
+            }
+            let filled = data.len();
+            data.resize(len, 0);
+            if let Some(cnt) = self.overlay_buffered(epoch, pos, &mut data, filled) {
+                data.truncate(cnt);
+                return Ok(data);
+            }
+        }
+    }
+
+    // The flush runs as a task of its own, so a caller that gives up waiting cannot abandon a
+    // batch half written, nor leave a cancelled write to land on top of a later flush. The next
+    // flush waits for it on `flush_lock`.
+    async fn flush_task(&self) -> Result<()> {
+        let shared = self.0.clone();
+        match tokio::spawn(async move { shared.flush().await }).await {
+            Ok(res) => res,
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    }
+
+    fn arm_timer(&self) {
+        let Some(delay) = self.0.max_delay else {
+            return;
+        };
+        {
+            let mut state = self.0.state();
+            if state.timer_armed || state.dirty.is_empty() {
+                return;
+            }
+            state.timer_armed = true;
+        }
+        let shared: Weak<Shared<B>> = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Some(shared) = shared.upgrade() {
+                shared.state().timer_armed = false;
+                // A failed flush leaves the data dirty; the next flush reports the error.
+                let _ = shared.flush().await;
+            }
+        });
+    }
+}
+
+impl<B: FileIo> Shared<B> {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let _guard = self.flush_lock.lock().await;
+        let extents = {
+            let mut state = self.state();
+            // Whatever an interrupted flush left behind goes out again, underneath anything
+            // written since.
+            let mut batch = std::mem::take(&mut state.flushing);
+            for (at, newer) in std::mem::take(&mut state.dirty) {
+                let _ = insert(&mut batch, at, &newer);
+            }
+            state.dirty_bytes = 0;
+            state.flushing = batch;
+            state.flushing.clone()
+        };
+        let mut remaining = extents.into_iter();
+        while let Some((pos, data)) = remaining.next() {
+            let (res, pos, data) = write_all(&self.inner, pos, data).await;
+            if let Err(e) = res {
+                // Put back whatever did not make it, underneath anything written since.
+                let mut state = self.state();
+                let mut dirty: Extents = std::iter::once((pos, data)).chain(remaining).collect();
+                for (at, newer) in std::mem::take(&mut state.dirty) {
+                    let _ = insert(&mut dirty, at, &newer);
+                }
+                state.dirty_bytes = dirty.values().map(Vec::len).sum();
+                state.dirty = dirty;
+                state.flushing.clear();
+                state.epoch += 1;
+                return Err(e);
+            }
+        }
+        let mut state = self.state();
+        state.flushing.clear();
+        state.epoch += 1;
+        Ok(())
+    }
+}
+
+// Returns where the unwritten rest of `data` starts along with the rest itself, so a failed flush
+// can put back exactly what is missing.
+async fn write_all<B: FileIo>(
+    io: &B,
+    mut pos: u64,
+    mut data: Vec<u8>,
+) -> (Result<()>, u64, Vec<u8>) {
+    while !data.is_empty() {
+        let (res, buf) = io.write_owned_at(pos, data).await;
+        data = buf;
+        match res {
+            Ok(0) => return (Err(std::io::ErrorKind::WriteZero.into()), pos, data),
+            Ok(n) => {
+                pos += n as u64;
+                data.drain(..n);
+            }
+            Err(e) => return (Err(e), pos, data),
+        }
+    }
+    (Ok(()), pos, data)
+}
+
+impl<B: FileIo + 'static> FileIo for BufferedWriter<B> {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let epoch = self.0.state().epoch;
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self
+                    .0
+                    .inner
+                    .read_at(pos + filled as u64, &mut buf[filled..])
+                    .await?
+                {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if let Some(cnt) = self.overlay_buffered(epoch, pos, buf, filled) {
+                return Ok(cnt);
+            }
+        }
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        let full = {
+            let mut state = self.0.state();
+            state.dirty_bytes += insert(&mut state.dirty, pos, buf);
+            state.dirty_bytes >= self.0.capacity
+        };
+        if full {
+            self.flush_task().await?;
+        } else {
+            self.arm_timer();
+        }
+        Ok(buf.len())
+    }
+
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let data = self.read_owned(pos, buf.len()).await?;
+        copy_to_uninit(buf, &data);
+        Ok(data.len())
+    }
+
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, mut buf: T) -> (Result<usize>, T) {
+        let len = buf.bytes_total() - buf.bytes_init();
+        match self.read_owned(pos, len).await {
+            Ok(data) => {
+                copy_to_uninit(spare(&mut buf), &data);
+                unsafe { buf.set_init(buf.bytes_init() + data.len()) };
+                (Ok(data.len()), buf)
+            }
+            Err(e) => (Err(e), buf),
+        }
+    }
+
+    // The data is copied into the buffered extents before anything is awaited.
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let res = self.write_at(pos, init_slice(&buf)).await;
+        (res, buf)
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.flush_task().await?;
+        self.0.inner.sync_all().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.sync().await
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.flush_task().await?;
+        self.0.inner.set_len(size).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        let meta = self.0.inner.metadata().await?;
+        let end = {
+            let state = self.0.state();
+            extents_end(&state.dirty).max(extents_end(&state.flushing))
+        };
+        Ok(if end > meta.len() {
+            meta.with_len(end)
+        } else {
+            meta
+        })
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.0.inner.kind()
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        self.0.inner.descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+
+    #[test]
+    fn insert_merges_touching_extents() {
+        let mut extents = Extents::new();
+        assert_eq!(insert(&mut extents, 10, &[1; 10]), 10);
+        assert_eq!(insert(&mut extents, 30, &[2; 10]), 10);
+        assert_eq!(extents.len(), 2);
+        // Adjacent on the left, overlapping on the right.
+        assert_eq!(insert(&mut extents, 20, &[3; 15]), 10);
+        assert_eq!(extents.len(), 1);
+        let mut expected = vec![1; 10];
+        expected.extend([3; 15]);
+        expected.extend([2; 5]);
+        assert_eq!(extents[&10], expected);
+        assert_eq!(insert(&mut extents, 0, &[4; 50]), 20);
+        assert_eq!(extents[&0], vec![4; 50]);
+        assert_eq!(insert(&mut extents, 60, &[]), 0);
+        assert_eq!(extents.len(), 1);
+    }
+
+    #[test]
+    fn overlay_only_touches_the_overlap() {
+        let mut extents = Extents::new();
+        insert(&mut extents, 5, &[1; 4]);
+        insert(&mut extents, 12, &[2; 4]);
+        let mut buf = [0; 10];
+        overlay(&extents, 7, &mut buf);
+        assert_eq!(buf, [1, 1, 0, 0, 0, 2, 2, 2, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn small_writes_stay_buffered_until_flushed() {
+        let mem = MemFile::new();
+        let writer = BufferedWriter::new(File::from_io(mem.clone()), 1024);
+        for i in 0..10u8 {
+            assert_eq!(writer.write_at(u64::from(i) * 4, &[i; 4]).await.unwrap(), 4);
+        }
+        assert_eq!(writer.buffered_bytes(), 40);
+        assert!(mem.contents().is_empty());
+        assert_eq!(writer.metadata().await.unwrap().len(), 40);
+        let mut buf = [0; 8];
+        assert_eq!(writer.read_at(16, &mut buf).await.unwrap(), 8);
+        assert_eq!(buf, [4, 4, 4, 4, 5, 5, 5, 5]);
+        writer.flush().await.unwrap();
+        assert_eq!(writer.buffered_bytes(), 0);
+        let expected: Vec<u8> = (0..10u8).flat_map(|i| [i; 4]).collect();
+        assert_eq!(mem.contents(), expected);
+    }
+
+    #[tokio::test]
+    async fn reads_overlay_buffered_data_on_the_file() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[1; 16]).await.unwrap();
+        let writer = BufferedWriter::new(File::from_io(mem.clone()), 1024);
+        writer.write_at(4, &[2; 4]).await.unwrap();
+        writer.write_at(20, &[3; 4]).await.unwrap();
+        let mut buf = [9; 32];
+        assert_eq!(writer.read_at(0, &mut buf).await.unwrap(), 24);
+        assert_eq!(&buf[..8], &[1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(&buf[16..24], &[0, 0, 0, 0, 3, 3, 3, 3]);
+        let (res, owned) = writer.read_owned_at(2, Vec::with_capacity(6)).await;
+        assert_eq!(res.unwrap(), 6);
+        assert_eq!(owned, [1, 1, 2, 2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn reaching_capacity_flushes() {
+        let mem = MemFile::new();
+        let writer = BufferedWriter::new(File::from_io(mem.clone()), 16);
+        writer.write_at(0, &[1; 10]).await.unwrap();
+        assert!(mem.contents().is_empty());
+        writer.write_at(10, &[2; 10]).await.unwrap();
+        assert_eq!(writer.buffered_bytes(), 0);
+        assert_eq!(mem.contents().len(), 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_delay_flushes_in_the_background() {
+        let mem = MemFile::new();
+        let writer = BufferedWriter::with_max_delay(
+            File::from_io(mem.clone()),
+            1024,
+            Duration::from_millis(10),
+        );
+        writer.write_at(0, &[1; 10]).await.unwrap();
+        assert!(mem.contents().is_empty());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(mem.contents(), vec![1; 10]);
+        assert_eq!(writer.buffered_bytes(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_flush_keeps_the_data() {
+        let mem = MemFile::new().with_latency(Duration::from_millis(50));
+        let writer = BufferedWriter::new(File::from_io(mem.clone()), 1024);
+        writer.write_at(0, &[1; 10]).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(10), writer.flush()).await;
+        assert!(res.is_err());
+        let mut buf = [0; 10];
+        assert_eq!(writer.read_at(0, &mut buf).await.unwrap(), 10);
+        assert_eq!(buf, [1; 10]);
+        writer.write_at(5, &[2; 10]).await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(mem.contents(), [[1; 5], [2; 5], [2; 5]].concat());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_write_that_flushes_keeps_the_data() {
+        let mem = MemFile::new().with_latency(Duration::from_millis(50));
+        let writer = BufferedWriter::new(File::from_io(mem.clone()), 16);
+        writer.write_at(0, &[1; 10]).await.unwrap();
+        let res =
+            tokio::time::timeout(Duration::from_millis(10), writer.write_at(10, &[2; 10])).await;
+        assert!(res.is_err());
+        writer.flush().await.unwrap();
+        assert_eq!(mem.contents(), [[1; 10], [2; 10]].concat());
+    }
+}
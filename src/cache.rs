@@ -0,0 +1,379 @@
+use crate::buf::{spare, zeroed, Limit};
+use crate::{BackendKind, File, FileIo, IoBuf, IoBufMut, Metadata, Native};
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::OnceCell;
+
+type Block = Arc<OnceCell<Arc<[u8]>>>;
+
+#[derive(Debug)]
+struct Slot {
+    block: Block,
+    stamp: u64,
+    referenced: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    slots: HashMap<u64, Slot>,
+    // CLOCK hand. Entries whose stamp no longer matches the slot were invalidated and are skipped.
+    ring: VecDeque<(u64, u64)>,
+    next_stamp: u64,
+}
+
+impl State {
+    fn lookup(&mut self, idx: u64, capacity: usize) -> (Block, bool) {
+        if let Some(slot) = self.slots.get_mut(&idx) {
+            slot.referenced = true;
+            return (slot.block.clone(), true);
+        }
+        while self.slots.len() >= capacity {
+            self.evict();
+        }
+        if self.ring.len() > capacity * 2 {
+            let slots = &self.slots;
+            self.ring
+                .retain(|(idx, stamp)| slots.get(idx).is_some_and(|slot| slot.stamp == *stamp));
+        }
+        let block = Block::default();
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.slots.insert(
+            idx,
+            Slot {
+                block: block.clone(),
+                stamp,
+                referenced: false,
+            },
+        );
+        self.ring.push_back((idx, stamp));
+        (block, false)
+    }
+
+    fn forget(&mut self, idx: u64, block: &Block) {
+        if self
+            .slots
+            .get(&idx)
+            .is_some_and(|slot| Arc::ptr_eq(&slot.block, block))
+        {
+            self.slots.remove(&idx);
+        }
+    }
+
+    fn evict(&mut self) {
+        while let Some((idx, stamp)) = self.ring.pop_front() {
+            match self.slots.get_mut(&idx) {
+                Some(slot) if slot.stamp == stamp => {
+                    if slot.referenced {
+                        slot.referenced = false;
+                        self.ring.push_back((idx, stamp));
+                    } else {
+                        self.slots.remove(&idx);
+                        return;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CachedFile<B = Native> {
+    inner: B,
+    block_size: usize,
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<B: FileIo> CachedFile<B> {
+    // `capacity` is in bytes and rounded down to whole blocks, keeping at least one.
+    pub fn new(file: File<B>, block_size: usize, capacity: usize) -> Self {
+        let block_size = block_size.max(1);
+        Self {
+            inner: file.into_inner(),
+            block_size,
+            capacity: (capacity / block_size).max(1),
+            state: Mutex::new(State::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn into_file(self) -> File<Self> {
+        File::from_io(self)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn cached_blocks(&self) -> usize {
+        self.state().slots.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn invalidate(&self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+        let bs = self.block_size as u64;
+        let (first, last) = (range.start / bs, (range.end - 1) / bs);
+        let mut state = self.state();
+        if last - first >= state.slots.len() as u64 {
+            state.slots.retain(|idx, _| *idx < first || *idx > last);
+        } else {
+            for idx in first..=last {
+                state.slots.remove(&idx);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.slots.clear();
+        state.ring.clear();
+    }
+
+    // Reads that would flush the whole cache are not worth caching.
+    fn bypasses(&self, pos: u64, len: usize) -> bool {
+        let bs = self.block_size as u64;
+        (pos % bs + len as u64).div_ceil(bs) > self.capacity as u64
+    }
+
+    // The block is read into a buffer the read owns: a cancelled reader may drop the load half
+    // way, and a blocking read must not be left filling freed memory.
+    async fn load(&self, idx: u64) -> Result<Arc<[u8]>> {
+        let pos = idx * self.block_size as u64;
+        let mut buf = Vec::with_capacity(self.block_size);
+        while buf.len() < self.block_size {
+            let want = self.block_size - buf.len();
+            let (res, limited) = self
+                .inner
+                .read_owned_at(pos + buf.len() as u64, Limit::new(buf, want))
+                .await;
+            buf = limited.into_inner();
+            if res? == 0 {
+                break;
+            }
+        }
+        Ok(buf.into())
+    }
+
+    async fn block(&self, idx: u64) -> Result<Arc<[u8]>> {
+        let (block, hit) = self.state().lookup(idx, self.capacity);
+        if hit && block.initialized() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        // Concurrent misses on the same block wait on the same cell; if the load fails the next
+        // waiter tries again instead of everyone seeing the first error.
+        let data = block.get_or_try_init(|| self.load(idx)).await.cloned()?;
+        // A short block ends at EOF, and a write past EOF would not invalidate it, so it is
+        // never kept.
+        if data.len() < self.block_size {
+            self.state().forget(idx, &block);
+        }
+        Ok(data)
+    }
+}
+
+impl<B: FileIo> FileIo for CachedFile<B> {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        if self.bypasses(pos, buf.len()) {
+            return self.inner.read_at(pos, buf).await;
+        }
+        let bs = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let block = self.block(at / bs).await?;
+            let offset = (at % bs) as usize;
+            if offset >= block.len() {
+                break;
+            }
+            let cnt = (block.len() - offset).min(buf.len() - done);
+            buf[done..done + cnt].copy_from_slice(&block[offset..offset + cnt]);
+            done += cnt;
+            if block.len() < self.block_size {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        // Invalidate once the write is done, even if it failed part way, so that no load which
+        // raced with it can leave stale data behind.
+        let res = self.inner.write_at(pos, buf).await;
+        self.invalidate(pos..pos.saturating_add(buf.len() as u64));
+        res
+    }
+
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        if self.bypasses(pos, buf.len()) {
+            return self.inner.read_uninit_at(pos, buf).await;
+        }
+        self.read_at(pos, zeroed(buf)).await
+    }
+
+    // Cached reads only copy out of blocks the cache owns, so the caller's buffer is never handed
+    // to the inner file and can be borrowed.
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, mut buf: T) -> (Result<usize>, T) {
+        if self.bypasses(pos, buf.bytes_total() - buf.bytes_init()) {
+            return self.inner.read_owned_at(pos, buf).await;
+        }
+        let res = self.read_at(pos, zeroed(spare(&mut buf))).await;
+        if let Ok(cnt) = res {
+            unsafe { buf.set_init(buf.bytes_init() + cnt) };
+        }
+        (res, buf)
+    }
+
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let len = buf.bytes_init() as u64;
+        let res = self.inner.write_owned_at(pos, buf).await;
+        self.invalidate(pos..pos.saturating_add(len));
+        res
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.inner.sync_all().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        let res = self.inner.set_len(size).await;
+        self.clear();
+        res
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        self.inner.metadata().await
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.inner.kind()
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        self.inner.descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+
+    fn cached(mem: &MemFile) -> CachedFile<MemFile> {
+        CachedFile::new(File::from_io(mem.clone()), 64, 64 * 4)
+    }
+
+    #[tokio::test]
+    async fn repeated_reads_hit() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[7; 256]).await.unwrap();
+        let cache = cached(&mem);
+        let mut buf = [0; 100];
+        assert_eq!(cache.read_at(10, &mut buf).await.unwrap(), 100);
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert_eq!(cache.read_at(10, &mut buf).await.unwrap(), 100);
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+        assert_eq!(buf, [7; 100]);
+    }
+
+    #[tokio::test]
+    async fn writes_invalidate_overlapping_blocks() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[1; 256]).await.unwrap();
+        let cache = cached(&mem);
+        let mut buf = [0; 256];
+        cache.read_at(0, &mut buf).await.unwrap();
+        assert_eq!(cache.cached_blocks(), 4);
+        cache.write_at(70, &[2; 10]).await.unwrap();
+        assert_eq!(cache.cached_blocks(), 3);
+        cache.read_at(0, &mut buf).await.unwrap();
+        assert_eq!(&buf[68..82], &[1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn capacity_is_respected() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[1; 64 * 16]).await.unwrap();
+        let cache = cached(&mem);
+        let mut buf = [0; 64];
+        for idx in 0..16 {
+            cache.read_at(idx * 64, &mut buf).await.unwrap();
+        }
+        assert_eq!(cache.cached_blocks(), 4);
+    }
+
+    #[tokio::test]
+    async fn reads_see_writes_past_old_eof() {
+        let mem = MemFile::new();
+        let cache = cached(&mem);
+        cache.write_at(0, &[1; 10]).await.unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(cache.read_at(0, &mut buf).await.unwrap(), 10);
+        cache.write_at(100, &[2; 28]).await.unwrap();
+        let mut buf = [0; 128];
+        assert_eq!(cache.read_at(0, &mut buf).await.unwrap(), 128);
+        assert_eq!(&buf[..10], &[1; 10]);
+        assert_eq!(&buf[10..100], &[0; 90]);
+        assert_eq!(&buf[100..], &[2; 28]);
+    }
+
+    #[tokio::test]
+    async fn reads_see_appends_through_the_inner_file() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[1; 100]).await.unwrap();
+        let cache = cached(&mem);
+        let mut buf = [0; 64];
+        assert_eq!(cache.read_at(64, &mut buf).await.unwrap(), 36);
+        mem.write_at(100, &[2; 4096]).await.unwrap();
+        assert_eq!(cache.read_at(64, &mut buf).await.unwrap(), 64);
+        assert_eq!(&buf[36..], &[2; 28]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_load_is_retried_by_the_next_reader() {
+        let mem = MemFile::new().with_latency(std::time::Duration::from_millis(50));
+        mem.write_at(0, &[3; 64]).await.unwrap();
+        let cache = cached(&mem);
+        let mut buf = [0; 64];
+        let res = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            cache.read_at(0, &mut buf),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(cache.read_at(0, &mut buf).await.unwrap(), 64);
+        assert_eq!(buf, [3; 64]);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::ranges::read_range;
+use crate::{File, FileIo, Native, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type ReadChunk<'a> = Pin<Box<dyn Future<Output = Result<Bytes>> + Send + 'a>>;
+
+enum Slot<'a> {
+    Reading(ReadChunk<'a>, usize),
+    Done(Result<Bytes>, usize),
+}
+
+pub struct Chunks<'a, B = Native> {
+    file: &'a File<B>,
+    pos: u64,
+    end: u64,
+    chunk_size: u64,
+    prefetch: usize,
+    queue: VecDeque<Slot<'a>>,
+    done: bool,
+}
+
+impl<'a, B: FileIo> Chunks<'a, B> {
+    pub(crate) fn new(
+        file: &'a File<B>,
+        range: Range<u64>,
+        chunk_size: usize,
+        prefetch: usize,
+    ) -> Self {
+        Self {
+            file,
+            pos: range.start,
+            end: range.end.max(range.start),
+            chunk_size: chunk_size.max(1) as u64,
+            prefetch: prefetch.max(1),
+            queue: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<'a, B: FileIo> Stream for Chunks<'a, B> {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        while this.queue.len() < this.prefetch && this.pos < this.end {
+            let len = this.chunk_size.min(this.end - this.pos);
+            // Each read owns its buffer, so dropping the stream with reads still queued is safe
+            // on every backend.
+            let read = read_range(this.file, this.pos..this.pos + len);
+            this.queue
+                .push_back(Slot::Reading(Box::pin(read), len as usize));
+            this.pos += len;
+        }
+        // Everything queued is polled, not just the head, so the read-ahead is actually in flight
+        // while the caller works through earlier chunks.
+        for slot in this.queue.iter_mut() {
+            if let Slot::Reading(read, len) = slot {
+                if let Poll::Ready(res) = read.as_mut().poll(cx) {
+                    *slot = Slot::Done(res, *len);
+                }
+            }
+        }
+        if let Some(Slot::Reading(..)) = this.queue.front() {
+            return Poll::Pending;
+        }
+        let Some(Slot::Done(res, len)) = this.queue.pop_front() else {
+            this.done = true;
+            return Poll::Ready(None);
+        };
+        match res {
+            // A short chunk means the file ended; later reads would only come back empty.
+            Ok(data) if data.len() < len => {
+                this.done = true;
+                this.queue.clear();
+                if data.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Ok(data)))
+                }
+            }
+            Ok(data) => Poll::Ready(Some(Ok(data))),
+            Err(e) => {
+                this.done = true;
+                this.queue.clear();
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::{IoBufMut, Metadata};
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    // Only serves reads into owned buffers, the way every prefetched chunk has to be read.
+    struct OwnedOnly(MemFile);
+
+    impl FileIo for OwnedOnly {
+        async fn read_at(&self, _pos: u64, _buf: &mut [u8]) -> std::io::Result<usize> {
+            panic!("chunks must not lend buffers to read_at");
+        }
+
+        async fn read_owned_at<T: IoBufMut>(
+            &self,
+            pos: u64,
+            buf: T,
+        ) -> (std::io::Result<usize>, T) {
+            self.0.read_owned_at(pos, buf).await
+        }
+
+        async fn write_at(&self, pos: u64, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write_at(pos, buf).await
+        }
+
+        async fn sync_all(&self) -> std::io::Result<()> {
+            self.0.sync_all().await
+        }
+
+        async fn sync_data(&self) -> std::io::Result<()> {
+            self.0.sync_data().await
+        }
+
+        async fn set_len(&self, size: u64) -> std::io::Result<()> {
+            self.0.set_len(size).await
+        }
+
+        async fn metadata(&self) -> std::io::Result<Metadata> {
+            self.0.metadata().await
+        }
+    }
+
+    async fn next<B: FileIo>(chunks: &mut Chunks<'_, B>) -> Option<Result<Bytes>> {
+        poll_fn(|cx| Pin::new(&mut *chunks).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn chunks_cover_the_range_and_stop_at_eof() {
+        let mem = MemFile::new();
+        let data: Vec<u8> = (0..250).collect();
+        mem.write_at(0, &data).await.unwrap();
+        let file = File::from_io(OwnedOnly(mem));
+        let mut chunks = file.chunks(10..1000, 100, 3);
+        let mut lens = Vec::new();
+        let mut seen = Vec::new();
+        while let Some(chunk) = next(&mut chunks).await {
+            let chunk = chunk.unwrap();
+            lens.push(chunk.len());
+            seen.extend_from_slice(&chunk);
+        }
+        assert_eq!(lens, [100, 100, 40]);
+        assert_eq!(seen, &data[10..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prefetched_reads_overlap() {
+        let mem = MemFile::new().with_latency(Duration::from_millis(10));
+        mem.write_at(0, &[1; 400]).await.unwrap();
+        let file = File::from_io(OwnedOnly(mem));
+        let start = tokio::time::Instant::now();
+        let mut chunks = file.chunks(0..400, 100, 4);
+        assert_eq!(next(&mut chunks).await.unwrap().unwrap().len(), 100);
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        for _ in 0..3 {
+            next(&mut chunks).await.unwrap().unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        // Dropping a stream part way, with read-ahead still queued, is the usual way to stop.
+        let mut chunks = file.chunks(0..400, 100, 4);
+        next(&mut chunks).await.unwrap().unwrap();
+        drop(chunks);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{Error, File, OpenOptions, Operation, Result};
+use std::path::Path;
+
+const BUF_SIZE: usize = 128 * 1024;
+
+pub async fn copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<u64> {
+    let src = File::open(src).await?;
+    let meta = src.metadata().await?;
+    let len = meta.len();
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(meta) = meta.as_std() {
+            options.mode(meta.permissions().mode());
+        }
+    }
+    let dst = options.open(dst).await?;
+
+    #[cfg(target_os = "linux")]
+    {
+        if src.reflink_to(&dst).await.is_ok() {
+            return Ok(len);
+        }
+    }
+
+    let mut pos = 0;
+
+    #[cfg(target_os = "linux")]
+    while pos < len {
+        let chunk = (len - pos).min(isize::MAX as u64) as usize;
+        match src.copy_range_to(&dst, pos, pos, chunk).await {
+            Ok(0) => break,
+            Ok(cnt) => pos += cnt as u64,
+            Err(e) if is_unsupported(&e) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    pump(&src, &dst, pos).await
+}
+
+async fn pump(src: &File, dst: &File, mut pos: u64) -> Result<u64> {
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let cnt = src.read_at(pos, &mut buf).await?;
+        if cnt == 0 {
+            return Ok(pos);
+        }
+        let mut written = 0;
+        while written < cnt {
+            match dst
+                .write_at(pos + written as u64, &buf[written..cnt])
+                .await?
+            {
+                0 => {
+                    return Err(Error::new(
+                        Operation::WriteAt,
+                        std::io::ErrorKind::WriteZero.into(),
+                    )
+                    .with_backend(dst.backend())
+                    .with_range(pos + written as u64, (cnt - written) as u64))
+                }
+                n => written += n,
+            }
+        }
+        pos += cnt as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_unsupported(e: &Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+    )
+}
@@ -0,0 +1,261 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BackendKind {
+    IoUring,
+    ThreadPool,
+    Aio,
+    Overlapped,
+    Memory,
+    Custom,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::IoUring => "io_uring",
+            BackendKind::ThreadPool => "thread pool",
+            BackendKind::Aio => "aio",
+            BackendKind::Overlapped => "overlapped",
+            BackendKind::Memory => "memory",
+            BackendKind::Custom => "custom",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    Open,
+    Create,
+    Metadata,
+    ReadAt,
+    WriteAt,
+    SyncAll,
+    SyncData,
+    SyncRange,
+    SetLen,
+    SetPermissions,
+    SetOwner,
+    SetTimes,
+    Clone,
+    Persist,
+    Copy,
+    CopyRange,
+    Reflink,
+    Send,
+    Splice,
+    Seek,
+    Fiemap,
+    Lock,
+    Unlock,
+    Map,
+    Prefetch,
+    Flush,
+    Advise,
+    Readahead,
+    GetXattr,
+    SetXattr,
+    ListXattrs,
+    RemoveXattr,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Open => "open",
+            Operation::Create => "create",
+            Operation::Metadata => "metadata",
+            Operation::ReadAt => "read_at",
+            Operation::WriteAt => "write_at",
+            Operation::SyncAll => "sync_all",
+            Operation::SyncData => "sync_data",
+            Operation::SyncRange => "sync_range",
+            Operation::SetLen => "set_len",
+            Operation::SetPermissions => "set_permissions",
+            Operation::SetOwner => "set_owner",
+            Operation::SetTimes => "set_times",
+            Operation::Clone => "try_clone",
+            Operation::Persist => "persist_at",
+            Operation::Copy => "copy",
+            Operation::CopyRange => "copy_range_to",
+            Operation::Reflink => "reflink",
+            Operation::Send => "send_to",
+            Operation::Splice => "splice_to",
+            Operation::Seek => "seek",
+            Operation::Fiemap => "physical_extents",
+            Operation::Lock => "lock",
+            Operation::Unlock => "unlock",
+            Operation::Map => "map",
+            Operation::Prefetch => "prefetch",
+            Operation::Flush => "flush",
+            Operation::Advise => "advise",
+            Operation::Readahead => "readahead",
+            Operation::GetXattr => "get_xattr",
+            Operation::SetXattr => "set_xattr",
+            Operation::ListXattrs => "list_xattrs",
+            Operation::RemoveXattr => "remove_xattr",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    operation: Operation,
+    backend: Option<BackendKind>,
+    offset: Option<u64>,
+    len: Option<u64>,
+    path: Option<PathBuf>,
+    attribute: Option<OsString>,
+    source: std::io::Error,
+}
+
+impl Error {
+    pub fn new(operation: Operation, source: std::io::Error) -> Self {
+        Self {
+            operation,
+            backend: None,
+            offset: None,
+            len: None,
+            path: None,
+            attribute: None,
+            source,
+        }
+    }
+
+    pub(crate) fn with_backend(mut self, backend: BackendKind) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    pub(crate) fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub(crate) fn with_range(mut self, offset: u64, len: u64) -> Self {
+        self.offset = Some(offset);
+        self.len = Some(len);
+        self
+    }
+
+    pub(crate) fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub(crate) fn with_attribute(mut self, name: impl Into<OsString>) -> Self {
+        self.attribute = Some(name.into());
+        self
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    pub fn backend(&self) -> Option<BackendKind> {
+        self.backend
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.len
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Name of the extended attribute an xattr operation was about.
+    pub fn attribute(&self) -> Option<&OsStr> {
+        self.attribute.as_deref()
+    }
+
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.source.kind()
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    // The extended attribute does not exist (ENODATA).
+    #[cfg(target_os = "linux")]
+    pub fn is_no_data(&self) -> bool {
+        self.raw_os_error() == Some(libc::ENODATA)
+    }
+
+    // A buffer was too small for an attribute value or list, or a name or value is larger than
+    // the filesystem allows (ERANGE).
+    #[cfg(target_os = "linux")]
+    pub fn is_range(&self) -> bool {
+        self.raw_os_error() == Some(libc::ERANGE)
+    }
+
+    pub fn io_error(&self) -> &std::io::Error {
+        &self.source
+    }
+
+    pub fn into_io_error(self) -> std::io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.operation)?;
+        if let Some(path) = &self.path {
+            write!(f, " for {}", path.display())?;
+        }
+        if let Some(name) = &self.attribute {
+            write!(f, " on attribute {:?}", name)?;
+        }
+        match (self.offset, self.len) {
+            (Some(offset), Some(len)) => write!(f, " at offset {offset} (len {len})")?,
+            (Some(offset), None) => write!(f, " at offset {offset}")?,
+            _ => (),
+        }
+        if let Some(backend) = self.backend {
+            write!(f, " [{backend}]")?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// The context travels inside the `io::Error` and can be recovered with `get_ref`/`into_inner`
+// followed by a downcast, so converting back and forth does not lose anything.
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::new(e.kind(), e)
+    }
+}
+
+impl TryFrom<std::io::Error> for Error {
+    type Error = std::io::Error;
+
+    fn try_from(e: std::io::Error) -> std::result::Result<Self, std::io::Error> {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let kind = e.kind();
+            match e.into_inner().map(|inner| inner.downcast::<Error>()) {
+                Some(Ok(inner)) => return Ok(*inner),
+                Some(Err(inner)) => return Err(std::io::Error::new(kind, inner)),
+                None => return Err(kind.into()),
+            }
+        }
+        Err(e)
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{File, Result};
+use futures_core::Stream;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type NextExtent<'a> = Pin<Box<dyn Future<Output = Result<Option<Range<u64>>>> + Send + 'a>>;
+
+pub struct Extents<'a> {
+    file: &'a File,
+    pos: u64,
+    next: Option<NextExtent<'a>>,
+    done: bool,
+}
+
+impl<'a> Extents<'a> {
+    pub(crate) fn new(file: &'a File) -> Self {
+        Self {
+            file,
+            pos: 0,
+            next: None,
+            done: false,
+        }
+    }
+
+    async fn next_extent(file: &'a File, pos: u64) -> Result<Option<Range<u64>>> {
+        let start = match file.next_data(pos).await? {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let end = match file.next_hole(start).await? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        Ok(Some(start..end))
+    }
+}
+
+impl<'a> Stream for Extents<'a> {
+    type Item = Result<Range<u64>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let (file, pos) = (self.file, self.pos);
+        let next = self
+            .next
+            .get_or_insert_with(|| Box::pin(Self::next_extent(file, pos)));
+        let res = match next.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(res) => res,
+        };
+        self.next = None;
+        match res {
+            Ok(Some(range)) => {
+                self.pos = range.end;
+                Poll::Ready(Some(Ok(range)))
+            }
+            Ok(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Err(e) => {
+                self.done = true;
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+}
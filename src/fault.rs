@@ -0,0 +1,451 @@
+use crate::buf::init_slice;
+use crate::{BackendKind, File, FileIo, IoBuf, IoBufMut, Metadata, Native, Operation};
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Error(i32),
+    TornWrite { keep: usize },
+}
+
+#[cfg(unix)]
+impl Fault {
+    pub fn eio() -> Self {
+        Fault::Error(libc::EIO)
+    }
+
+    pub fn enospc() -> Self {
+        Fault::Error(libc::ENOSPC)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoggedOp {
+    WriteAt { pos: u64, data: Vec<u8> },
+    SetLen(u64),
+    SyncAll,
+    SyncData,
+}
+
+#[derive(Debug)]
+struct Rule {
+    op: Operation,
+    fault: Fault,
+    skip: usize,
+    remaining: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Undo {
+    pos: u64,
+    old: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    rules: Vec<Rule>,
+    undo: Vec<Undo>,
+    synced_len: Option<u64>,
+    log: Vec<LoggedOp>,
+    synced_log: usize,
+}
+
+#[derive(Debug)]
+pub struct FaultyFile<B = Native> {
+    inner: B,
+    state: Mutex<State>,
+    mutate: tokio::sync::Mutex<()>,
+}
+
+impl<B: FileIo> FaultyFile<B> {
+    // Writes and truncations read the bytes they replace so that `crash` can restore them, so
+    // the file must be open for reading as well as writing. A write-only file is rejected here
+    // rather than failing every write later.
+    pub async fn new(file: File<B>) -> crate::Result<Self> {
+        if let Err(e) = file.read_at(0, &mut []).await {
+            if !not_readable(&e) {
+                return Err(e);
+            }
+            return Err(crate::Error::new(
+                Operation::Open,
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "FaultyFile needs a file opened for both reading and writing",
+                ),
+            )
+            .with_backend(file.backend()));
+        }
+        Ok(Self {
+            inner: file.into_inner(),
+            state: Mutex::new(State::default()),
+            mutate: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn into_file(self) -> File<Self> {
+        File::from_io(self)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn inject(&self, op: Operation, fault: Fault) {
+        self.inject_after(op, fault, 0);
+    }
+
+    pub fn inject_after(&self, op: Operation, fault: Fault, skip: usize) {
+        self.state().rules.push(Rule {
+            op,
+            fault,
+            skip,
+            remaining: Some(1),
+        });
+    }
+
+    pub fn inject_always(&self, op: Operation, fault: Fault) {
+        self.state().rules.push(Rule {
+            op,
+            fault,
+            skip: 0,
+            remaining: None,
+        });
+    }
+
+    pub fn clear_faults(&self) {
+        self.state().rules.clear();
+    }
+
+    pub fn log(&self) -> Vec<LoggedOp> {
+        self.state().log.clone()
+    }
+
+    pub fn take_log(&self) -> Vec<LoggedOp> {
+        let mut state = self.state();
+        state.synced_log = 0;
+        std::mem::take(&mut state.log)
+    }
+
+    pub fn unsynced_writes(&self) -> usize {
+        self.state().undo.len()
+    }
+
+    fn take_fault(&self, op: Operation) -> Option<Fault> {
+        let mut state = self.state();
+        let idx = state.rules.iter_mut().position(|rule| {
+            if rule.op != op {
+                return false;
+            }
+            if rule.skip > 0 {
+                rule.skip -= 1;
+                return false;
+            }
+            true
+        })?;
+        let rule = &mut state.rules[idx];
+        let fault = rule.fault;
+        if let Some(remaining) = &mut rule.remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                state.rules.remove(idx);
+            }
+        }
+        Some(fault)
+    }
+
+    fn check(&self, op: Operation) -> Result<Option<Fault>> {
+        match self.take_fault(op) {
+            Some(Fault::Error(code)) => Err(std::io::Error::from_raw_os_error(code)),
+            fault => Ok(fault),
+        }
+    }
+
+    // Remembers the length the file had at the last sync before the first unsynced change, so
+    // a crash can put it back.
+    async fn note_len(&self) -> Result<()> {
+        if self.state().synced_len.is_none() {
+            let len = self.inner.metadata().await?.len();
+            self.state().synced_len.get_or_insert(len);
+        }
+        Ok(())
+    }
+
+    fn synced(&self, op: LoggedOp) {
+        let mut state = self.state();
+        state.undo.clear();
+        state.synced_len = None;
+        state.log.push(op);
+        state.synced_log = state.log.len();
+    }
+
+    // The inner file only ever sees buffers owned by this wrapper, so a cancelled write cannot
+    // leave the backend holding on to the caller's memory or to the undo copy.
+    async fn write_logged(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        let fault = self.check(Operation::WriteAt)?;
+        let _guard = self.mutate.lock().await;
+        self.note_len().await?;
+        let data = match fault {
+            Some(Fault::TornWrite { keep }) => &buf[..keep.min(buf.len())],
+            _ => buf,
+        };
+        let (res, mut old) = self
+            .inner
+            .read_owned_at(pos, Vec::with_capacity(data.len()))
+            .await;
+        res?;
+        let (res, mut logged) = self.inner.write_owned_at(pos, data.to_vec()).await;
+        let written = res?;
+        old.truncate(written);
+        logged.truncate(written);
+        let mut state = self.state();
+        state.undo.push(Undo { pos, old });
+        state.log.push(LoggedOp::WriteAt { pos, data: logged });
+        // A torn write reports success for the whole buffer, like a device that lost power
+        // after acknowledging it.
+        Ok(if data.len() < buf.len() && written == data.len() {
+            buf.len()
+        } else {
+            written
+        })
+    }
+
+    pub async fn crash(&self) -> Result<()> {
+        let _guard = self.mutate.lock().await;
+        // Everything after the last sync is rolled back, so it is dropped from the log as well
+        // and replaying the log reproduces the state after the crash.
+        let (undo, synced_len) = {
+            let mut state = self.state();
+            let synced_log = state.synced_log.min(state.log.len());
+            state.log.truncate(synced_log);
+            (std::mem::take(&mut state.undo), state.synced_len.take())
+        };
+        for entry in undo.iter().rev() {
+            write_all(&self.inner, entry.pos, &entry.old).await?;
+        }
+        if let Some(len) = synced_len {
+            self.inner.set_len(len).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn not_readable(e: &crate::Error) -> bool {
+    e.raw_os_error() == Some(libc::EBADF)
+}
+
+#[cfg(windows)]
+fn not_readable(e: &crate::Error) -> bool {
+    e.kind() == std::io::ErrorKind::PermissionDenied
+}
+
+async fn write_all<B: FileIo>(io: &B, mut pos: u64, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match io.write_at(pos, buf).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                pos += n as u64;
+                buf = &buf[n..];
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<B: FileIo> FileIo for FaultyFile<B> {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.check(Operation::ReadAt)?;
+        self.inner.read_at(pos, buf).await
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        self.write_logged(pos, buf).await
+    }
+
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        self.check(Operation::ReadAt)?;
+        self.inner.read_uninit_at(pos, buf).await
+    }
+
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        if let Err(e) = self.check(Operation::ReadAt) {
+            return (Err(e), buf);
+        }
+        self.inner.read_owned_at(pos, buf).await
+    }
+
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let res = self.write_logged(pos, init_slice(&buf)).await;
+        (res, buf)
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.check(Operation::SyncAll)?;
+        let _guard = self.mutate.lock().await;
+        self.inner.sync_all().await?;
+        self.synced(LoggedOp::SyncAll);
+        Ok(())
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.check(Operation::SyncData)?;
+        let _guard = self.mutate.lock().await;
+        self.inner.sync_data().await?;
+        self.synced(LoggedOp::SyncData);
+        Ok(())
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.check(Operation::SetLen)?;
+        let _guard = self.mutate.lock().await;
+        self.note_len().await?;
+        let len = self.inner.metadata().await?.len();
+        if size < len {
+            let (res, old) = self
+                .inner
+                .read_owned_at(size, Vec::with_capacity((len - size) as usize))
+                .await;
+            res?;
+            self.state().undo.push(Undo { pos: size, old });
+        }
+        self.inner.set_len(size).await?;
+        self.state().log.push(LoggedOp::SetLen(size));
+        Ok(())
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        self.check(Operation::Metadata)?;
+        self.inner.metadata().await
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.inner.kind()
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        self.inner.descriptor()
+    }
+}
+
+pub async fn replay<B: FileIo>(ops: &[LoggedOp], target: &File<B>) -> crate::Result<()> {
+    for op in ops {
+        match op {
+            LoggedOp::WriteAt { pos, data } => {
+                let mut done = 0;
+                while done < data.len() {
+                    match target.write_at(pos + done as u64, &data[done..]).await? {
+                        0 => {
+                            return Err(crate::Error::new(
+                                Operation::WriteAt,
+                                std::io::ErrorKind::WriteZero.into(),
+                            )
+                            .with_backend(target.backend())
+                            .with_range(pos + done as u64, (data.len() - done) as u64))
+                        }
+                        n => done += n,
+                    }
+                }
+            }
+            LoggedOp::SetLen(size) => target.set_len(*size).await?,
+            LoggedOp::SyncAll => target.sync_all().await?,
+            LoggedOp::SyncData => target.sync_data().await?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+
+    struct WriteOnly(MemFile);
+
+    impl FileIo for WriteOnly {
+        async fn read_at(&self, _pos: u64, _buf: &mut [u8]) -> Result<usize> {
+            Err(std::io::Error::from_raw_os_error(libc::EBADF))
+        }
+
+        async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+            self.0.write_at(pos, buf).await
+        }
+
+        async fn sync_all(&self) -> Result<()> {
+            self.0.sync_all().await
+        }
+
+        async fn sync_data(&self) -> Result<()> {
+            self.0.sync_data().await
+        }
+
+        async fn set_len(&self, size: u64) -> Result<()> {
+            self.0.set_len(size).await
+        }
+
+        async fn metadata(&self) -> Result<Metadata> {
+            self.0.metadata().await
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_write_only_files() {
+        let file = File::from_io(WriteOnly(MemFile::new()));
+        let err = FaultyFile::new(file).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn crash_rolls_back_to_last_sync() {
+        let mem = MemFile::new();
+        let faulty = FaultyFile::new(File::from_io(mem.clone())).await.unwrap();
+        let file = File::from_io(faulty);
+        file.write_at(0, b"hello world").await.unwrap();
+        file.sync_data().await.unwrap();
+        file.write_at(6, b"there, friend").await.unwrap();
+        file.set_len(8).await.unwrap();
+        assert_eq!(mem.contents(), b"hello th");
+        assert_eq!(file.get_ref().unsynced_writes(), 2);
+
+        file.get_ref().crash().await.unwrap();
+        assert_eq!(mem.contents(), b"hello world");
+        assert_eq!(
+            file.get_ref().log(),
+            [
+                LoggedOp::WriteAt {
+                    pos: 0,
+                    data: b"hello world".to_vec()
+                },
+                LoggedOp::SyncData,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn torn_write_keeps_a_prefix_and_reports_success() {
+        let mem = MemFile::new();
+        let faulty = FaultyFile::new(File::from_io(mem.clone())).await.unwrap();
+        faulty.inject(Operation::WriteAt, Fault::TornWrite { keep: 3 });
+        let file = File::from_io(faulty);
+        assert_eq!(file.write_at(0, b"abcdef").await.unwrap(), 6);
+        assert_eq!(mem.contents(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn injected_errors_fire_once_after_skipping() {
+        let faulty = FaultyFile::new(File::from_io(MemFile::new()))
+            .await
+            .unwrap();
+        faulty.inject_after(Operation::SyncData, Fault::eio(), 1);
+        let file = File::from_io(faulty);
+        file.sync_data().await.unwrap();
+        let err = file.sync_data().await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        file.sync_data().await.unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{BackendKind, Operation};
+use std::future::Future;
+use std::io::Result;
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+pub(crate) trait Transferred {
+    fn bytes(&self) -> Option<u64>;
+}
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+impl Transferred for usize {
+    fn bytes(&self) -> Option<u64> {
+        Some(*self as u64)
+    }
+}
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+impl Transferred for () {
+    fn bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+impl Transferred for crate::Metadata {
+    fn bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Wraps results that carry no byte count, such as locks or extent lists.
+struct Untracked<T>(T);
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+impl<T> Transferred for Untracked<T> {
+    fn bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+// Without `tracing` only the metrics hook looks at these, and it does not care about the
+// descriptor or the range.
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OpInfo {
+    pub(crate) backend: BackendKind,
+    pub(crate) descriptor: Option<i64>,
+    pub(crate) op: Operation,
+    pub(crate) offset: Option<u64>,
+    pub(crate) len: Option<u64>,
+}
+
+#[cfg(not(any(feature = "tracing", feature = "metrics")))]
+pub(crate) async fn instrument<T, F>(_info: OpInfo, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    fut.await
+}
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+pub(crate) async fn instrument<T, F>(info: OpInfo, fut: F) -> Result<T>
+where
+    T: Transferred,
+    F: Future<Output = Result<T>>,
+{
+    #[cfg(feature = "metrics")]
+    let start = std::time::Instant::now();
+
+    #[cfg(feature = "tracing")]
+    let res = {
+        use tracing::Instrument;
+
+        let span = tracing::trace_span!(
+            "async_file",
+            op = %info.op,
+            fd = info.descriptor,
+            offset = info.offset,
+            len = info.len,
+            backend = %info.backend,
+            result = tracing::field::Empty,
+        );
+        let res = fut.instrument(span.clone()).await;
+        match &res {
+            Ok(val) => match val.bytes() {
+                Some(bytes) => span.record("result", bytes),
+                None => span.record("result", "ok"),
+            },
+            Err(e) => span.record("result", tracing::field::display(e)),
+        };
+        res
+    };
+
+    #[cfg(not(feature = "tracing"))]
+    let res = fut.await;
+
+    #[cfg(feature = "metrics")]
+    crate::metrics::record(crate::metrics::OpEvent {
+        backend: info.backend,
+        op: info.op,
+        latency: start.elapsed(),
+        bytes: res.as_ref().ok().and_then(Transferred::bytes),
+        ok: res.is_ok(),
+    });
+
+    res
+}
+
+pub(crate) async fn instrument_status<T, F>(info: OpInfo, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    instrument(info, async { fut.await.map(Untracked) })
+        .await
+        .map(|done| done.0)
+}
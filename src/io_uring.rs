@@ -1,4 +1,7 @@
+use crate::BackendKind;
 use std::io::Result;
+use std::marker::PhantomData;
+use std::mem::{ManuallyDrop, MaybeUninit};
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
 use std::path::Path;
 use std::sync::OnceLock;
@@ -21,7 +24,10 @@ pub(crate) fn init_uring() -> Result<()> {
             }
             Ok(())
         }
-        Err(e) => Err(std::io::Error::from(e.kind())),
+        Err(e) => Err(match e.raw_os_error() {
+            Some(code) => std::io::Error::from_raw_os_error(code),
+            None => std::io::Error::new(e.kind(), e.to_string()),
+        }),
     }
 }
 
@@ -29,6 +35,32 @@ unsafe fn uring() -> &'static rio::Rio {
     &*UNSAFE_URING.0
 }
 
+// Hands uninitialized memory to rio as a bare iovec, which only the kernel writes through, so it
+// never has to be zeroed or viewed as `&mut [u8]` first.
+struct Uninit<'a>(libc::iovec, PhantomData<&'a mut [MaybeUninit<u8>]>);
+
+unsafe impl Send for Uninit<'_> {}
+
+unsafe impl Sync for Uninit<'_> {}
+
+impl<'a> Uninit<'a> {
+    fn new(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        let iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        Self(iov, PhantomData)
+    }
+}
+
+impl rio::AsIoVec for Uninit<'_> {
+    fn into_new_iovec(&self) -> libc::iovec {
+        self.0
+    }
+}
+
+impl rio::AsIoVecMut for Uninit<'_> {}
+
 #[derive(Debug)]
 pub struct File(tokio::fs::File);
 
@@ -63,28 +95,48 @@ impl File {
         unsafe { uring().read_at(&self.0, &buf, pos).await }
     }
 
+    pub(crate) async fn read_uninit_at(
+        &self,
+        pos: u64,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> Result<usize> {
+        let iov = Uninit::new(buf);
+        unsafe { uring().read_at(&self.0, &iov, pos).await }
+    }
+
     pub async fn sync_all(&self) -> Result<()> {
-        unsafe {
-            let f = std::fs::File::from_raw_fd(self.0.as_raw_fd());
-            uring().fsync(&f).await?;
-            std::mem::forget(f)
-        }
-        Ok(())
+        // rio wants a `std::fs::File`; borrow the descriptor without ever closing it, including
+        // when the fsync fails.
+        let f = ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(self.0.as_raw_fd()) });
+        unsafe { uring().fsync(&f).await }
     }
 
     pub async fn sync_data(&self) -> Result<()> {
-        unsafe {
-            let f = std::fs::File::from_raw_fd(self.0.as_raw_fd());
-            uring().fdatasync(&f).await?;
-            std::mem::forget(f)
-        }
-        Ok(())
+        // rio's fdatasync sets IORING_FSYNC_DATASYNC in the SQE flags instead of the fsync
+        // flags, which the kernel rejects with EBADF, so this goes through the blocking pool.
+        self.0.sync_data().await
     }
 
     pub async fn set_len(&self, size: u64) -> Result<()> {
         self.0.set_len(size).await
     }
 
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        self.0.set_permissions(perm).await
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        Ok(Self(self.0.try_clone().await?))
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        BackendKind::IoUring
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        self.0
+    }
+
     pub(crate) unsafe fn unsafe_from_file(file: tokio::fs::File) -> Self {
         Self(file)
     }
@@ -1,13 +1,52 @@
-use std::io::Result;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use std::mem::MaybeUninit;
+#[cfg(feature = "bytes")]
+use std::ops::Range;
 use std::path::Path;
+use tokio::io::ReadBuf;
+
+#[cfg(target_os = "linux")]
+use std::ffi::{OsStr, OsString};
 
 #[cfg(unix)]
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 
 #[cfg(windows)]
-use std::os::windows::io::{AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, RawHandle};
+use std::os::windows::io::{
+    AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, IntoRawHandle, OwnedHandle, RawHandle,
+};
 
+mod backend;
+mod buf;
+mod buffered;
+mod cache;
+#[cfg(feature = "bytes")]
+mod chunks;
+mod copy;
+mod error;
+#[cfg(target_os = "linux")]
+mod extents;
+pub mod fault;
+mod instrument;
+#[cfg(target_os = "linux")]
+mod lock;
+pub mod mem;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(target_os = "linux")]
+mod mmap;
 mod options;
+#[cfg(feature = "bytes")]
+mod ranges;
+mod sched;
+#[cfg(feature = "bytes")]
+mod sink;
+mod throttle;
+#[cfg(target_os = "linux")]
+mod writeback;
+#[cfg(target_os = "linux")]
+mod xattr;
 
 #[cfg(target_os = "linux")]
 pub(crate) mod linux;
@@ -63,101 +102,751 @@ use bsd::File as FileImpl;
 #[cfg(target_os = "windows")]
 use windows::File as FileImpl;
 
+pub use backend::{FileIo, Metadata, Native};
+pub use buf::{IoBuf, IoBufMut};
+pub use buffered::BufferedWriter;
+pub use cache::CachedFile;
+#[cfg(feature = "bytes")]
+pub use chunks::Chunks;
+pub use copy::copy;
+pub use error::{BackendKind, Error, Operation, Result};
+#[cfg(target_os = "linux")]
+pub use extents::Extents;
+use instrument::{instrument, instrument_status, OpInfo};
+#[cfg(target_os = "linux")]
+pub use linux::{Advice, PhysicalExtent, SyncRangeFlags};
+#[cfg(target_os = "linux")]
+pub use lock::{FileLock, LockKind, RangeLock};
+#[cfg(target_os = "linux")]
+pub use mmap::{Mmap, MmapMut};
 pub use options::OpenOptions;
+#[cfg(feature = "bytes")]
+pub use ranges::ReadRangesOptions;
+pub use sched::{IoClass, IoPriority, IoScheduler, ScheduledFile};
+#[cfg(feature = "bytes")]
+pub use sink::{WriteSink, WriteStreamError};
+pub use throttle::{Throttle, ThrottledFile};
+#[cfg(target_os = "linux")]
+pub use writeback::WritebackScheduler;
+
+pub struct File<B = Native>(B);
+
+impl<B: FileIo> File<B> {
+    pub fn from_io(io: B) -> Self {
+        Self(io)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> B {
+        self.0
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        self.0.kind()
+    }
+
+    fn error(&self, operation: Operation, e: std::io::Error) -> Error {
+        Error::new(operation, e).with_backend(self.0.kind())
+    }
+
+    fn op(&self, op: Operation, offset: Option<u64>, len: Option<u64>) -> OpInfo {
+        OpInfo {
+            backend: self.0.kind(),
+            descriptor: self.0.descriptor(),
+            op,
+            offset,
+            len,
+        }
+    }
+
+    pub async fn metadata(&self) -> Result<Metadata> {
+        let info = self.op(Operation::Metadata, None, None);
+        instrument(info, self.0.metadata())
+            .await
+            .map_err(|e| self.error(Operation::Metadata, e))
+    }
+
+    pub async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        let len = buf.len() as u64;
+        let info = self.op(Operation::WriteAt, Some(pos), Some(len));
+        instrument(info, self.0.write_at(pos, buf))
+            .await
+            .map_err(|e| self.error(Operation::WriteAt, e).with_range(pos, len))
+    }
+
+    pub async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len() as u64;
+        let info = self.op(Operation::ReadAt, Some(pos), Some(len));
+        instrument(info, self.0.read_at(pos, buf))
+            .await
+            .map_err(|e| self.error(Operation::ReadAt, e).with_range(pos, len))
+    }
+
+    pub async fn sync_all(&self) -> Result<()> {
+        let info = self.op(Operation::SyncAll, None, None);
+        instrument(info, self.0.sync_all())
+            .await
+            .map_err(|e| self.error(Operation::SyncAll, e))
+    }
+
+    pub async fn sync_data(&self) -> Result<()> {
+        let info = self.op(Operation::SyncData, None, None);
+        instrument(info, self.0.sync_data())
+            .await
+            .map_err(|e| self.error(Operation::SyncData, e))
+    }
 
-pub struct File(FileImpl);
+    pub async fn set_len(&self, size: u64) -> Result<()> {
+        let info = self.op(Operation::SetLen, Some(size), None);
+        instrument(info, self.0.set_len(size))
+            .await
+            .map_err(|e| self.error(Operation::SetLen, e).with_offset(size))
+    }
+
+    // io_uring reads straight into `buf`. The thread pool cannot lend it to a blocking task that
+    // may outlive a dropped future, so it reads into a temporary buffer and copies that out.
+    pub async fn read_at_uninit(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let len = buf.len() as u64;
+        let info = self.op(Operation::ReadAt, Some(pos), Some(len));
+        instrument(info, self.0.read_uninit_at(pos, buf))
+            .await
+            .map_err(|e| self.error(Operation::ReadAt, e).with_range(pos, len))
+    }
+
+    pub async fn read_buf_at(&self, pos: u64, buf: &mut ReadBuf<'_>) -> Result<usize> {
+        let cnt = self
+            .read_at_uninit(pos, unsafe { buf.unfilled_mut() })
+            .await?;
+        unsafe { buf.assume_init(cnt) };
+        buf.advance(cnt);
+        Ok(cnt)
+    }
+
+    // Appends up to `len` bytes to `buf`, stopping early only at the end of the file. Only the
+    // spare capacity is read into, so dropping the future keeps what `buf` already held along
+    // with every chunk appended so far.
+    pub async fn read_to_vec_at(&self, pos: u64, len: usize, buf: &mut Vec<u8>) -> Result<usize> {
+        buf.reserve(len);
+        let start = buf.len();
+        while buf.len() - start < len {
+            let done = buf.len() - start;
+            let spare = &mut buf.spare_capacity_mut()[..len - done];
+            let cnt = self.read_at_uninit(pos + done as u64, spare).await?;
+            if cnt == 0 {
+                break;
+            }
+            unsafe { buf.set_len(buf.len() + cnt) };
+        }
+        Ok(buf.len() - start)
+    }
+
+    pub async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let len = buf.bytes_init() as u64;
+        let info = self.op(Operation::WriteAt, Some(pos), Some(len));
+        let mut out = None;
+        let res = instrument(info, async {
+            let (res, buf) = self.0.write_owned_at(pos, buf).await;
+            out = Some(buf);
+            res
+        })
+        .await
+        .map_err(|e| self.error(Operation::WriteAt, e).with_range(pos, len));
+        (res, out.expect("operation completed"))
+    }
+
+    pub async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let len = (buf.bytes_total() - buf.bytes_init()) as u64;
+        let info = self.op(Operation::ReadAt, Some(pos), Some(len));
+        let mut out = None;
+        let res = instrument(info, async {
+            let (res, buf) = self.0.read_owned_at(pos, buf).await;
+            out = Some(buf);
+            res
+        })
+        .await
+        .map_err(|e| self.error(Operation::ReadAt, e).with_range(pos, len));
+        (res, out.expect("operation completed"))
+    }
+
+    #[cfg(feature = "bytes")]
+    pub async fn read_bytes_at(&self, pos: u64, len: usize) -> Result<Bytes> {
+        let mut buf = bytes::BytesMut::with_capacity(len);
+        while buf.len() < len {
+            let (res, filled) = self.read_owned_at(pos + buf.len() as u64, buf).await;
+            buf = filled;
+            if res? == 0 {
+                break;
+            }
+        }
+        buf.truncate(len);
+        Ok(buf.freeze())
+    }
+
+    #[cfg(feature = "bytes")]
+    pub async fn write_bytes_at(&self, pos: u64, buf: Bytes) -> Result<usize> {
+        self.write_owned_at(pos, buf).await.0
+    }
+
+    #[cfg(feature = "bytes")]
+    pub async fn read_ranges(&self, ranges: &[Range<u64>]) -> Result<Vec<Bytes>> {
+        ranges::read_ranges(self, ranges, &ReadRangesOptions::default()).await
+    }
+
+    #[cfg(feature = "bytes")]
+    pub async fn read_ranges_with(
+        &self,
+        ranges: &[Range<u64>],
+        options: &ReadRangesOptions,
+    ) -> Result<Vec<Bytes>> {
+        ranges::read_ranges(self, ranges, options).await
+    }
+
+    #[cfg(feature = "bytes")]
+    pub fn chunks(&self, range: Range<u64>, chunk_size: usize, prefetch: usize) -> Chunks<'_, B> {
+        Chunks::new(self, range, chunk_size, prefetch)
+    }
+
+    #[cfg(feature = "bytes")]
+    pub fn write_sink(&self, start: u64, max_in_flight: usize) -> WriteSink<'_, B> {
+        WriteSink::new(self, start, max_in_flight)
+    }
+
+    #[cfg(feature = "bytes")]
+    pub async fn write_stream<S>(
+        &self,
+        start: u64,
+        stream: S,
+        max_in_flight: usize,
+    ) -> std::result::Result<u64, WriteStreamError>
+    where
+        S: futures_core::Stream<Item = Bytes>,
+    {
+        sink::write_stream(self, start, stream, max_in_flight).await
+    }
+}
 
 impl File {
+    fn sys(&self) -> &FileImpl {
+        &self.0 .0
+    }
+
     pub(crate) async fn open_with_options(
         options: &tokio::fs::OpenOptions,
         path: impl AsRef<Path>,
     ) -> Result<Self> {
-        Ok(Self(FileImpl::open_with_options(options, path).await?))
+        let path = path.as_ref();
+        match FileImpl::open_with_options(options, path).await {
+            Ok(file) => Ok(Self(Native(file))),
+            Err(e) => Err(Error::new(Operation::Open, e).with_path(path)),
+        }
     }
 
     pub async fn create(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self(FileImpl::create(path).await?))
+        let path = path.as_ref();
+        match FileImpl::create(path).await {
+            Ok(file) => Ok(Self(Native(file))),
+            Err(e) => Err(Error::new(Operation::Create, e).with_path(path)),
+        }
     }
 
     pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self(FileImpl::open(path).await?))
+        let path = path.as_ref();
+        match FileImpl::open(path).await {
+            Ok(file) => Ok(Self(Native(file))),
+            Err(e) => Err(Error::new(Operation::Open, e).with_path(path)),
+        }
     }
 
-    pub async fn metadata(&self) -> Result<std::fs::Metadata> {
-        self.0.metadata().await
+    // Gives a file from `tempfile_in` a name. Fails with `ErrorKind::Unsupported` when the
+    // filesystem lacks O_TMPFILE, since the unlinked fallback file cannot be linked again.
+    #[cfg(target_os = "linux")]
+    pub async fn persist_at(&self, dir: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<()> {
+        let (dir, name) = (dir.as_ref(), name.as_ref());
+        let info = self.op(Operation::Persist, None, None);
+        instrument_status(info, self.sys().persist_at(dir, name))
+            .await
+            .map_err(|e| self.error(Operation::Persist, e).with_path(dir.join(name)))
     }
 
-    pub async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
-        self.0.write_at(pos, buf).await
+    #[cfg(target_os = "linux")]
+    pub async fn copy_range_to(
+        &self,
+        dst: &File,
+        src_off: u64,
+        dst_off: u64,
+        len: usize,
+    ) -> Result<usize> {
+        let info = self.op(Operation::CopyRange, Some(src_off), Some(len as u64));
+        instrument(
+            info,
+            self.sys().copy_range_to(dst.sys(), src_off, dst_off, len),
+        )
+        .await
+        .map_err(|e| {
+            self.error(Operation::CopyRange, e)
+                .with_range(src_off, len as u64)
+        })
     }
 
-    pub async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
-        self.0.read_at(pos, buf).await
+    #[cfg(target_os = "linux")]
+    pub async fn reflink_to(&self, dst: &File) -> Result<()> {
+        let info = self.op(Operation::Reflink, None, None);
+        instrument_status(info, self.sys().reflink_to(dst.sys()))
+            .await
+            .map_err(|e| self.error(Operation::Reflink, e))
     }
 
-    pub async fn sync_all(&self) -> Result<()> {
-        self.0.sync_all().await
+    #[cfg(target_os = "linux")]
+    pub async fn reflink_range_to(
+        &self,
+        dst: &File,
+        src_off: u64,
+        dst_off: u64,
+        len: u64,
+    ) -> Result<()> {
+        let info = self.op(Operation::Reflink, Some(src_off), Some(len));
+        let reflink = self
+            .sys()
+            .reflink_range_to(dst.sys(), src_off, dst_off, len);
+        instrument_status(info, reflink)
+            .await
+            .map_err(|e| self.error(Operation::Reflink, e).with_range(src_off, len))
     }
 
-    pub async fn sync_data(&self) -> Result<()> {
-        self.0.sync_data().await
+    #[cfg(target_os = "linux")]
+    pub async fn send_to(
+        &self,
+        stream: &tokio::net::TcpStream,
+        offset: u64,
+        len: usize,
+    ) -> Result<usize> {
+        let info = self.op(Operation::Send, Some(offset), Some(len as u64));
+        instrument(info, self.sys().send_to(stream, offset, len))
+            .await
+            .map_err(|e| {
+                self.error(Operation::Send, e)
+                    .with_range(offset, len as u64)
+            })
     }
 
-    pub async fn set_len(&self, size: u64) -> Result<()> {
-        self.0.set_len(size).await
+    #[cfg(target_os = "linux")]
+    pub async fn splice_to(
+        &self,
+        pipe: &tokio::net::unix::pipe::Sender,
+        offset: u64,
+        len: usize,
+    ) -> Result<usize> {
+        let info = self.op(Operation::Splice, Some(offset), Some(len as u64));
+        instrument(info, self.sys().splice_to(pipe, offset, len))
+            .await
+            .map_err(|e| {
+                self.error(Operation::Splice, e)
+                    .with_range(offset, len as u64)
+            })
+    }
+
+    // The file cursor is left where it was. Queries through this file and its clones are
+    // serialized, but anything else using the cursor at the same time, such as a handle from
+    // `into_std` of a clone, can observe it moved while a query runs.
+    #[cfg(target_os = "linux")]
+    pub async fn next_data(&self, offset: u64) -> Result<Option<u64>> {
+        let info = self.op(Operation::Seek, Some(offset), None);
+        instrument_status(info, self.sys().next_data(offset))
+            .await
+            .map_err(|e| self.error(Operation::Seek, e).with_offset(offset))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn next_hole(&self, offset: u64) -> Result<Option<u64>> {
+        let info = self.op(Operation::Seek, Some(offset), None);
+        instrument_status(info, self.sys().next_hole(offset))
+            .await
+            .map_err(|e| self.error(Operation::Seek, e).with_offset(offset))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn extents(&self) -> Extents<'_> {
+        Extents::new(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn physical_extents(&self) -> Result<Vec<PhysicalExtent>> {
+        let info = self.op(Operation::Fiemap, None, None);
+        instrument_status(info, self.sys().physical_extents())
+            .await
+            .map_err(|e| self.error(Operation::Fiemap, e))
+    }
+
+    // Whole-file flock. Shared guards taken through this file share one lock; asking for a lock
+    // that would convert one a live guard holds fails with EDEADLK instead, since the kernel
+    // would otherwise change it under that guard. Clones from `try_clone` count as this file.
+    #[cfg(target_os = "linux")]
+    pub async fn lock_shared(&self) -> Result<FileLock<'_>> {
+        let info = self.op(Operation::Lock, None, None);
+        instrument_status(
+            info,
+            lock::lock(self.as_fd(), self.sys().locks(), LockKind::Shared),
+        )
+        .await
+        .map_err(|e| self.error(Operation::Lock, e))
+    }
+
+    // Waiting polls with backoff so that it can be cancelled, which also means it is not fair:
+    // shared holders elsewhere that keep overlapping each other can starve it indefinitely.
+    #[cfg(target_os = "linux")]
+    pub async fn lock_exclusive(&self) -> Result<FileLock<'_>> {
+        let info = self.op(Operation::Lock, None, None);
+        instrument_status(
+            info,
+            lock::lock(self.as_fd(), self.sys().locks(), LockKind::Exclusive),
+        )
+        .await
+        .map_err(|e| self.error(Operation::Lock, e))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn try_lock_shared(&self) -> Result<Option<FileLock<'_>>> {
+        let info = self.op(Operation::Lock, None, None);
+        instrument_status(info, async {
+            lock::try_lock(self.as_fd(), self.sys().locks(), LockKind::Shared)
+        })
+        .await
+        .map_err(|e| self.error(Operation::Lock, e))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn try_lock_exclusive(&self) -> Result<Option<FileLock<'_>>> {
+        let info = self.op(Operation::Lock, None, None);
+        instrument_status(info, async {
+            lock::try_lock(self.as_fd(), self.sys().locks(), LockKind::Exclusive)
+        })
+        .await
+        .map_err(|e| self.error(Operation::Lock, e))
+    }
+
+    // OFD lock on `offset..offset + len`, or to the end of the file when `len` is 0. A range
+    // overlapping one a live guard from this file holds fails with EDEADLK, as the kernel would
+    // merge the two. Waits the same unfair way `lock_exclusive` does.
+    #[cfg(target_os = "linux")]
+    pub async fn lock_range(&self, offset: u64, len: u64, kind: LockKind) -> Result<RangeLock<'_>> {
+        let info = self.op(Operation::Lock, Some(offset), Some(len));
+        instrument_status(
+            info,
+            lock::lock_range(self.as_fd(), self.sys().locks(), offset, len, kind),
+        )
+        .await
+        .map_err(|e| self.error(Operation::Lock, e).with_range(offset, len))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn try_lock_range(
+        &self,
+        offset: u64,
+        len: u64,
+        kind: LockKind,
+    ) -> Result<Option<RangeLock<'_>>> {
+        let info = self.op(Operation::Lock, Some(offset), Some(len));
+        instrument_status(info, async {
+            lock::try_lock_range(self.as_fd(), self.sys().locks(), offset, len, kind)
+        })
+        .await
+        .map_err(|e| self.error(Operation::Lock, e).with_range(offset, len))
+    }
+
+    /// # Safety
+    ///
+    /// The mapped bytes must not be modified or truncated by anything else, including this
+    /// handle's `write_at` and `set_len`, while the returned `Mmap` is alive.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn map(&self, offset: u64, len: usize) -> Result<Mmap> {
+        Mmap::new(self.as_raw_fd(), offset, len)
+            .map_err(|e| self.error(Operation::Map, e).with_range(offset, len as u64))
+    }
+
+    /// # Safety
+    ///
+    /// The mapped bytes must not be modified or truncated by anything else, including this
+    /// handle's `write_at` and `set_len`, while the returned `MmapMut` is alive.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn map_mut(&self, offset: u64, len: usize) -> Result<MmapMut> {
+        MmapMut::new(self.as_raw_fd(), offset, len)
+            .map_err(|e| self.error(Operation::Map, e).with_range(offset, len as u64))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn advise(&self, offset: u64, len: u64, advice: Advice) -> Result<()> {
+        let info = self.op(Operation::Advise, Some(offset), Some(len));
+        instrument_status(info, self.sys().advise(offset, len, advice))
+            .await
+            .map_err(|e| self.error(Operation::Advise, e).with_range(offset, len))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn readahead(&self, offset: u64, len: usize) -> Result<()> {
+        let info = self.op(Operation::Readahead, Some(offset), Some(len as u64));
+        instrument_status(info, self.sys().readahead(offset, len))
+            .await
+            .map_err(|e| {
+                self.error(Operation::Readahead, e)
+                    .with_range(offset, len as u64)
+            })
+    }
+
+    // A missing attribute is reported as ENODATA, and a name or value the filesystem cannot
+    // hold as ERANGE; check for them with `Error::is_no_data` and `Error::is_range`.
+    #[cfg(target_os = "linux")]
+    pub async fn get_xattr(&self, name: impl AsRef<OsStr>) -> Result<Vec<u8>> {
+        let name = name.as_ref();
+        let info = self.op(Operation::GetXattr, None, None);
+        instrument_status(info, xattr::get(self.as_fd(), name))
+            .await
+            .map_err(|e| self.error(Operation::GetXattr, e).with_attribute(name))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_xattr(&self, name: impl AsRef<OsStr>, value: &[u8]) -> Result<()> {
+        let name = name.as_ref();
+        let info = self.op(Operation::SetXattr, None, Some(value.len() as u64));
+        instrument_status(info, xattr::set(self.as_fd(), name, value))
+            .await
+            .map_err(|e| self.error(Operation::SetXattr, e).with_attribute(name))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn list_xattrs(&self) -> Result<Vec<OsString>> {
+        let info = self.op(Operation::ListXattrs, None, None);
+        instrument_status(info, xattr::list(self.as_fd()))
+            .await
+            .map_err(|e| self.error(Operation::ListXattrs, e))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn remove_xattr(&self, name: impl AsRef<OsStr>) -> Result<()> {
+        let name = name.as_ref();
+        let info = self.op(Operation::RemoveXattr, None, None);
+        instrument_status(info, xattr::remove(self.as_fd(), name))
+            .await
+            .map_err(|e| self.error(Operation::RemoveXattr, e).with_attribute(name))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<()> {
+        let info = self.op(Operation::SyncRange, Some(offset), Some(len));
+        instrument_status(info, self.sys().sync_range(offset, len, flags))
+            .await
+            .map_err(|e| self.error(Operation::SyncRange, e).with_range(offset, len))
+    }
+
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        let info = self.op(Operation::SetPermissions, None, None);
+        instrument_status(info, self.sys().set_permissions(perm))
+            .await
+            .map_err(|e| self.error(Operation::SetPermissions, e))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let info = self.op(Operation::SetOwner, None, None);
+        instrument_status(info, self.sys().set_owner(uid, gid))
+            .await
+            .map_err(|e| self.error(Operation::SetOwner, e))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_times(
+        &self,
+        atime: Option<std::time::SystemTime>,
+        mtime: Option<std::time::SystemTime>,
+    ) -> Result<()> {
+        let info = self.op(Operation::SetTimes, None, None);
+        instrument_status(info, self.sys().set_times(atime, mtime))
+            .await
+            .map_err(|e| self.error(Operation::SetTimes, e))
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        let info = self.op(Operation::Clone, None, None);
+        match instrument_status(info, self.sys().try_clone()).await {
+            Ok(file) => Ok(Self(Native(file))),
+            Err(e) => Err(self.error(Operation::Clone, e)),
+        }
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        self.0 .0.into_tokio()
+    }
+
+    // Tokio only hands out the std file once nothing else holds a reference to it. Operations
+    // that were abandoned mid-flight keep one until their syscall returns, so wait for that.
+    pub async fn into_std(self) -> std::fs::File {
+        let mut file = self.into_tokio();
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match file.try_into_std() {
+                Ok(file) => return file,
+                Err(busy) => file = busy,
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // Same as `into_std`, for the conversion traits that cannot await. Blocks the calling thread
+    // for at most as long as an abandoned syscall takes to finish.
+    fn into_std_now(self) -> std::fs::File {
+        let mut file = self.into_tokio();
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match file.try_into_std() {
+                Ok(file) => return file,
+                Err(busy) => file = busy,
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_micros(50);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+
+// Creates an anonymous file in `dir`. Without O_TMPFILE support the file is created under a
+// random name and unlinked right away, and such a file cannot be passed to `persist_at`.
+#[cfg(target_os = "linux")]
+pub async fn tempfile_in(dir: impl AsRef<Path>) -> Result<File> {
+    let dir = dir.as_ref();
+    match FileImpl::tempfile_in(dir).await {
+        Ok(file) => Ok(File(Native(file))),
+        Err(e) => Err(Error::new(Operation::Create, e).with_path(dir)),
     }
 }
 
 impl From<tokio::fs::File> for File {
     fn from(file: tokio::fs::File) -> Self {
-        Self(file.into())
+        Self(Native(file.into()))
     }
 }
 
 impl From<std::fs::File> for File {
     fn from(file: std::fs::File) -> Self {
-        Self(file.into())
+        Self(Native(file.into()))
     }
 }
 
 #[cfg(unix)]
 impl AsFd for File {
     fn as_fd(&self) -> BorrowedFd<'_> {
-        self.0.as_fd()
+        self.sys().as_fd()
     }
 }
 
 #[cfg(unix)]
 impl AsRawFd for File {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        self.sys().as_raw_fd()
     }
 }
 
 #[cfg(unix)]
 impl FromRawFd for File {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
-        Self(FileImpl::from_raw_fd(fd))
+        Self(Native(FileImpl::from_raw_fd(fd)))
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for File {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_std_now().into_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl From<File> for OwnedFd {
+    fn from(file: File) -> Self {
+        file.into_std_now().into()
+    }
+}
+
+#[cfg(unix)]
+impl TryFrom<OwnedFd> for File {
+    type Error = std::io::Error;
+
+    fn try_from(fd: OwnedFd) -> std::io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let file = std::fs::File::from(fd);
+        let file_type = file.metadata()?.file_type();
+        if file_type.is_dir() {
+            return Err(std::io::ErrorKind::IsADirectory.into());
+        }
+        if !file_type.is_file() && !file_type.is_block_device() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "file descriptor does not support positioned I/O",
+            ));
+        }
+        Ok(file.into())
     }
 }
 
 #[cfg(windows)]
 impl AsHandle for File {
     fn as_handle(&self) -> BorrowedHandle<'_> {
-        self.0.as_handle()
+        self.sys().as_handle()
     }
 }
 
 #[cfg(windows)]
 impl AsRawHandle for File {
     fn as_raw_handle(&self) -> RawHandle {
-        self.0.as_raw_handle()
+        self.sys().as_raw_handle()
     }
 }
 
 #[cfg(windows)]
 impl FromRawHandle for File {
     unsafe fn from_raw_handle(handle: RawHandle) -> Self {
-        Self(FileImpl::from_raw_handle(handle))
+        Self(Native(FileImpl::from_raw_handle(handle)))
+    }
+}
+
+#[cfg(windows)]
+impl IntoRawHandle for File {
+    fn into_raw_handle(self) -> RawHandle {
+        self.into_std_now().into_raw_handle()
+    }
+}
+
+#[cfg(windows)]
+impl From<File> for OwnedHandle {
+    fn from(file: File) -> Self {
+        file.into_std_now().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_read_to_vec_keeps_what_the_vec_held() {
+        let mem = MemFile::new().with_latency(Duration::from_millis(10));
+        mem.write_at(0, &[7; 64]).await.unwrap();
+        let file = File::from_io(mem);
+        let mut buf = vec![1, 2, 3];
+        let read = file.read_to_vec_at(0, 64, &mut buf);
+        assert!(tokio::time::timeout(Duration::from_millis(5), read)
+            .await
+            .is_err());
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(file.read_to_vec_at(0, 64, &mut buf).await.unwrap(), 64);
+        assert_eq!(&buf[..3], [1, 2, 3]);
+        assert!(buf[3..].iter().all(|&b| b == 7));
     }
 }
@@ -1,9 +1,110 @@
+use std::ffi::CString;
 use std::io::Result;
+use std::mem::MaybeUninit;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::Interest;
+use tokio::net::unix::pipe;
+use tokio::net::TcpStream;
 
+use crate::buf;
 use crate::io_uring;
+use crate::lock::LockTable;
 use crate::unix;
+use crate::{BackendKind, IoBuf, IoBufMut};
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+const FIEMAP_BATCH: usize = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalExtent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+    DontNeed,
+    NoReuse,
+}
+
+impl Advice {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => libc::POSIX_FADV_RANDOM,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+            Advice::NoReuse => libc::POSIX_FADV_NOREUSE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncRangeFlags(libc::c_uint);
+
+impl SyncRangeFlags {
+    pub const WAIT_BEFORE: Self = Self(libc::SYNC_FILE_RANGE_WAIT_BEFORE);
+    pub const WRITE: Self = Self(libc::SYNC_FILE_RANGE_WRITE);
+    pub const WAIT_AFTER: Self = Self(libc::SYNC_FILE_RANGE_WAIT_AFTER);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for SyncRangeFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for SyncRangeFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
 
 #[derive(Debug)]
 enum LinuxFile {
@@ -11,43 +112,368 @@ enum LinuxFile {
     Pos(unix::File),
 }
 
+// State that belongs to the open file description rather than to one handle, shared with
+// every handle `try_clone` makes of it.
+#[derive(Debug, Default)]
+struct Description {
+    locks: LockTable,
+    // Held around the save, seek and restore of the cursor, so that two queries cannot restore
+    // each other's saved position.
+    seek: Mutex<()>,
+}
+
 #[derive(Debug)]
-pub struct File(LinuxFile);
+pub struct File(LinuxFile, Arc<Description>);
 
 impl File {
+    fn new(file: LinuxFile) -> Self {
+        Self(file, Arc::default())
+    }
+
+    pub(crate) fn locks(&self) -> &LockTable {
+        &self.1.locks
+    }
+
     pub(crate) async fn open_with_options(
         options: &tokio::fs::OpenOptions,
         path: impl AsRef<Path>,
     ) -> Result<Self> {
-        match io_uring::File::open_with_options(options, path.as_ref()).await {
-            Ok(file) => Ok(Self(LinuxFile::Uring(file))),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::Unsupported) => Ok(Self(
-                LinuxFile::Pos(unix::File::open_with_options(options, path.as_ref()).await?),
-            )),
-            Err(e) => Err(e),
+        match io_uring::init_uring() {
+            Ok(()) => Ok(Self::new(LinuxFile::Uring(
+                io_uring::File::open_with_options(options, path).await?,
+            ))),
+            Err(_) => Ok(Self::new(LinuxFile::Pos(
+                unix::File::open_with_options(options, path).await?,
+            ))),
         }
     }
 
     pub async fn create(path: impl AsRef<Path>) -> Result<Self> {
-        match io_uring::File::create(path.as_ref()).await {
-            Ok(file) => Ok(Self(LinuxFile::Uring(file))),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::Unsupported) => Ok(Self(
-                LinuxFile::Pos(unix::File::create(path.as_ref()).await?),
-            )),
-            Err(e) => Err(e),
+        match io_uring::init_uring() {
+            Ok(()) => Ok(Self::new(LinuxFile::Uring(
+                io_uring::File::create(path).await?,
+            ))),
+            Err(_) => Ok(Self::new(LinuxFile::Pos(unix::File::create(path).await?))),
         }
     }
 
     pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
-        match io_uring::File::open(path.as_ref()).await {
-            Ok(file) => Ok(Self(LinuxFile::Uring(file))),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::Unsupported) => {
-                Ok(Self(LinuxFile::Pos(unix::File::open(path.as_ref()).await?)))
+        match io_uring::init_uring() {
+            Ok(()) => Ok(Self::new(LinuxFile::Uring(
+                io_uring::File::open(path).await?,
+            ))),
+            Err(_) => Ok(Self::new(LinuxFile::Pos(unix::File::open(path).await?))),
+        }
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        match &self.0 {
+            LinuxFile::Uring(file) => file.backend(),
+            LinuxFile::Pos(file) => file.backend(),
+        }
+    }
+
+    pub async fn tempfile_in(dir: impl AsRef<Path>) -> Result<Self> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE);
+        match Self::open_with_options(&options, dir.as_ref()).await {
+            Ok(file) => Ok(file),
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) | Some(libc::EINVAL)
+                ) =>
+            {
+                Self::tempfile_unlinked_in(dir.as_ref()).await
             }
             Err(e) => Err(e),
         }
     }
 
+    async fn tempfile_unlinked_in(dir: &Path) -> Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut options = tokio::fs::OpenOptions::new();
+        options.read(true).write(true).create_new(true).mode(0o600);
+        loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let path = dir.join(format!(
+                ".tmp{}.{}.{}",
+                std::process::id(),
+                nanos,
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match Self::open_with_options(&options, &path).await {
+                Ok(file) => {
+                    tokio::fs::remove_file(&path).await?;
+                    return Ok(file);
+                }
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::AlreadyExists) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn persist_at(&self, dir: impl AsRef<Path>, name: impl AsRef<Path>) -> Result<()> {
+        let fd = self.as_raw_fd();
+        let src = CString::new(format!("/proc/self/fd/{}", fd))?;
+        let dst = CString::new(dir.as_ref().join(name).as_os_str().as_bytes())?;
+        unix::asyncify(move || unsafe {
+            // Only O_TMPFILE inodes may be linked back once their last name is gone. The
+            // fallback in `tempfile_unlinked_in` creates ordinary files, and linkat would just
+            // report ENOENT for them.
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if flags & libc::O_TMPFILE != libc::O_TMPFILE {
+                let mut stat = MaybeUninit::<libc::stat>::uninit();
+                if libc::fstat(fd, stat.as_mut_ptr()) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if stat.assume_init().st_nlink == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "unlinked file was not created with O_TMPFILE and cannot be persisted",
+                    ));
+                }
+            }
+            if libc::linkat(
+                libc::AT_FDCWD,
+                src.as_ptr(),
+                libc::AT_FDCWD,
+                dst.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            ) < 0
+            {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn copy_range_to(
+        &self,
+        dst: &File,
+        src_off: u64,
+        dst_off: u64,
+        len: usize,
+    ) -> Result<usize> {
+        let src_fd = self.as_raw_fd();
+        let dst_fd = dst.as_raw_fd();
+        unix::asyncify(move || {
+            let mut src_off = src_off as libc::loff_t;
+            let mut dst_off = dst_off as libc::loff_t;
+            let cnt = unsafe {
+                libc::copy_file_range(src_fd, &mut src_off, dst_fd, &mut dst_off, len, 0)
+            };
+            if cnt < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(cnt as usize)
+            }
+        })
+        .await
+    }
+
+    pub async fn reflink_to(&self, dst: &File) -> Result<()> {
+        let src_fd = self.as_raw_fd();
+        let dst_fd = dst.as_raw_fd();
+        unix::asyncify(move || {
+            if unsafe { libc::ioctl(dst_fd, libc::FICLONE, src_fd) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn reflink_range_to(
+        &self,
+        dst: &File,
+        src_off: u64,
+        dst_off: u64,
+        len: u64,
+    ) -> Result<()> {
+        let range = libc::file_clone_range {
+            src_fd: self.as_raw_fd() as i64,
+            src_offset: src_off,
+            src_length: len,
+            dest_offset: dst_off,
+        };
+        let dst_fd = dst.as_raw_fd();
+        unix::asyncify(move || {
+            if unsafe { libc::ioctl(dst_fd, libc::FICLONERANGE, &range) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn send_to(&self, stream: &TcpStream, offset: u64, len: usize) -> Result<usize> {
+        let in_fd = self.as_raw_fd();
+        let out_fd = stream.as_raw_fd();
+        stream
+            .async_io(Interest::WRITABLE, || {
+                let mut off = offset as libc::off_t;
+                let cnt = unsafe { libc::sendfile(out_fd, in_fd, &mut off, len) };
+                if cnt < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(cnt as usize)
+                }
+            })
+            .await
+    }
+
+    pub async fn splice_to(&self, pipe: &pipe::Sender, offset: u64, len: usize) -> Result<usize> {
+        let in_fd = self.as_raw_fd();
+        let out_fd = pipe.as_raw_fd();
+        loop {
+            pipe.writable().await?;
+            let res = pipe.try_io(|| {
+                let mut off = offset as libc::loff_t;
+                let cnt = unsafe {
+                    libc::splice(
+                        in_fd,
+                        &mut off,
+                        out_fd,
+                        std::ptr::null_mut(),
+                        len,
+                        libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+                    )
+                };
+                if cnt < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(cnt as usize)
+                }
+            });
+            match res {
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock) => continue,
+                res => return res,
+            }
+        }
+    }
+
+    async fn seek(&self, offset: u64, whence: libc::c_int) -> Result<Option<u64>> {
+        let fd = self.as_raw_fd();
+        let desc = self.1.clone();
+        unix::asyncify(move || {
+            // lseek moves the cursor shared with every handle on this file description, which
+            // callers of `into_std` or `into_tokio` would see. Put it back afterwards.
+            let _serial = desc.seek.lock().unwrap_or_else(|e| e.into_inner());
+            let cursor = unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) };
+            if cursor < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let pos = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+            let res = if pos < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::ENXIO) {
+                    Ok(None)
+                } else {
+                    Err(err)
+                }
+            } else {
+                Ok(Some(pos as u64))
+            };
+            if unsafe { libc::lseek(fd, cursor, libc::SEEK_SET) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            res
+        })
+        .await
+    }
+
+    pub async fn next_data(&self, offset: u64) -> Result<Option<u64>> {
+        self.seek(offset, libc::SEEK_DATA).await
+    }
+
+    pub async fn next_hole(&self, offset: u64) -> Result<Option<u64>> {
+        self.seek(offset, libc::SEEK_HOLE).await
+    }
+
+    pub async fn physical_extents(&self) -> Result<Vec<PhysicalExtent>> {
+        let fd = self.as_raw_fd();
+        unix::asyncify(move || {
+            let mut extents = Vec::new();
+            let mut start = 0;
+            loop {
+                let mut map = Fiemap {
+                    fm_start: start,
+                    fm_length: u64::MAX - start,
+                    fm_flags: FIEMAP_FLAG_SYNC,
+                    fm_mapped_extents: 0,
+                    fm_extent_count: FIEMAP_BATCH as u32,
+                    fm_reserved: 0,
+                    fm_extents: [FiemapExtent::default(); FIEMAP_BATCH],
+                };
+                if unsafe { libc::ioctl(fd, FS_IOC_FIEMAP, &mut map) } < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let mapped = &map.fm_extents[..map.fm_mapped_extents as usize];
+                extents.extend(mapped.iter().map(|e| PhysicalExtent {
+                    logical: e.fe_logical,
+                    physical: e.fe_physical,
+                    length: e.fe_length,
+                    flags: e.fe_flags,
+                }));
+                match mapped.last() {
+                    Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                        start = last.fe_logical + last.fe_length;
+                    }
+                    _ => return Ok(extents),
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn advise(&self, offset: u64, len: u64, advice: Advice) -> Result<()> {
+        let fd = self.as_raw_fd();
+        unix::asyncify(move || {
+            let ret = unsafe {
+                libc::posix_fadvise(
+                    fd,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                    advice.as_raw(),
+                )
+            };
+            if ret != 0 {
+                Err(std::io::Error::from_raw_os_error(ret))
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn readahead(&self, offset: u64, len: usize) -> Result<()> {
+        let fd = self.as_raw_fd();
+        unix::asyncify(move || {
+            if unsafe { libc::readahead(fd, offset as libc::off64_t, len) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
     pub async fn metadata(&self) -> Result<std::fs::Metadata> {
         match &self.0 {
             LinuxFile::Uring(file) => file.metadata().await,
@@ -69,6 +495,48 @@ impl File {
         }
     }
 
+    pub(crate) async fn read_uninit_at(
+        &self,
+        pos: u64,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> Result<usize> {
+        match &self.0 {
+            // Borrowing is safe with rio for the same reason as in `read_owned_at`, so the kernel
+            // reads straight into `buf`.
+            LinuxFile::Uring(file) => file.read_uninit_at(pos, buf).await,
+            LinuxFile::Pos(file) => file.read_uninit_at(pos, buf).await,
+        }
+    }
+
+    pub(crate) async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        match &self.0 {
+            LinuxFile::Uring(file) => {
+                let res = file.write_at(pos, buf::init_slice(&buf)).await;
+                (res, buf)
+            }
+            LinuxFile::Pos(file) => file.write_owned_at(pos, buf).await,
+        }
+    }
+
+    pub(crate) async fn read_owned_at<T: IoBufMut>(
+        &self,
+        pos: u64,
+        mut buf: T,
+    ) -> (Result<usize>, T) {
+        match &self.0 {
+            // rio's completions wait for the kernel when dropped, which makes borrowing the spare
+            // capacity safe here, and the kernel fills it without it being zeroed first.
+            LinuxFile::Uring(file) => {
+                let res = file.read_uninit_at(pos, buf::spare(&mut buf)).await;
+                if let Ok(cnt) = res {
+                    unsafe { buf.set_init(buf.bytes_init() + cnt) };
+                }
+                (res, buf)
+            }
+            LinuxFile::Pos(file) => file.read_owned_at(pos, buf).await,
+        }
+    }
+
     pub async fn sync_all(&self) -> Result<()> {
         match &self.0 {
             LinuxFile::Uring(file) => file.sync_all().await,
@@ -83,21 +551,122 @@ impl File {
         }
     }
 
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<()> {
+        let fd = self.as_raw_fd();
+        unix::asyncify(move || {
+            let ret = unsafe {
+                libc::sync_file_range(fd, offset as libc::off64_t, len as libc::off64_t, flags.0)
+            };
+            if ret < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
     pub async fn set_len(&self, size: u64) -> Result<()> {
         match &self.0 {
             LinuxFile::Uring(file) => file.set_len(size).await,
             LinuxFile::Pos(file) => file.set_len(size).await,
         }
     }
+
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        match &self.0 {
+            LinuxFile::Uring(file) => file.set_permissions(perm).await,
+            LinuxFile::Pos(file) => file.set_permissions(perm).await,
+        }
+    }
+
+    pub async fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        // The blocking task can outlive a dropped future and this file with it, so it gets a
+        // descriptor of its own rather than a number that may be reused by then.
+        let fd = self.as_fd().try_clone_to_owned()?;
+        let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+        let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+        unix::asyncify(move || {
+            if unsafe { libc::fchown(fd.as_raw_fd(), uid, gid) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn set_times(
+        &self,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<()> {
+        let fd = self.as_fd().try_clone_to_owned()?;
+        let times = [to_timespec(atime), to_timespec(mtime)];
+        unix::asyncify(move || {
+            if unsafe { libc::futimens(fd.as_raw_fd(), times.as_ptr()) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        match &self.0 {
+            LinuxFile::Uring(file) => Ok(Self(
+                LinuxFile::Uring(file.try_clone().await?),
+                self.1.clone(),
+            )),
+            LinuxFile::Pos(file) => Ok(Self(
+                LinuxFile::Pos(file.try_clone().await?),
+                self.1.clone(),
+            )),
+        }
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        match self.0 {
+            LinuxFile::Uring(file) => file.into_tokio(),
+            LinuxFile::Pos(file) => file.into_tokio(),
+        }
+    }
+}
+
+fn to_timespec(time: Option<SystemTime>) -> libc::timespec {
+    let time = match time {
+        Some(time) => time,
+        None => {
+            return libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            }
+        }
+    };
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n as i64),
+            }
+        }
+    };
+    libc::timespec {
+        tv_sec: secs as libc::time_t,
+        tv_nsec: nanos as _,
+    }
 }
 
 impl From<tokio::fs::File> for File {
     fn from(file: tokio::fs::File) -> Self {
         match io_uring::init_uring() {
-            Ok(_) => Self(LinuxFile::Uring(unsafe {
+            Ok(_) => Self::new(LinuxFile::Uring(unsafe {
                 io_uring::File::unsafe_from_file(file)
             })),
-            Err(_) => Self(LinuxFile::Pos(unix::File::from(file))),
+            Err(_) => Self::new(LinuxFile::Pos(unix::File::from(file))),
         }
     }
 }
@@ -130,10 +699,10 @@ impl AsRawFd for File {
 impl FromRawFd for File {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
         match io_uring::init_uring() {
-            Ok(_) => Self(LinuxFile::Uring(unsafe {
+            Ok(_) => Self::new(LinuxFile::Uring(unsafe {
                 io_uring::File::unsafe_from_raw_fd(fd)
             })),
-            Err(_) => Self(LinuxFile::Pos(unix::File::from_raw_fd(fd))),
+            Err(_) => Self::new(LinuxFile::Pos(unix::File::from_raw_fd(fd))),
         }
     }
 }
@@ -0,0 +1,287 @@
+use std::io::Result;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    // Kind of the flock and how many guards share it.
+    whole: Option<(LockKind, usize)>,
+    // Byte ranges as (offset, len), where a len of 0 runs to the end of the file.
+    ranges: Vec<(u64, u64)>,
+}
+
+// flock and OFD locks belong to the open file description, so a second request through the
+// same description would silently convert or merge the lock it already holds, and dropping
+// either guard would release both. The table remembers what the guards hold so such requests
+// can be refused instead.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable(Mutex<Held>);
+
+impl LockTable {
+    fn held(&self) -> MutexGuard<'_, Held> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    let end = |(offset, len): (u64, u64)| {
+        if len == 0 {
+            u64::MAX
+        } else {
+            offset.saturating_add(len)
+        }
+    };
+    a.0 < end(b) && b.0 < end(a)
+}
+
+fn already_held() -> std::io::Error {
+    std::io::Error::from_raw_os_error(libc::EDEADLK)
+}
+
+// Holds a flock on the whole file until dropped. Shared guards taken through the same file
+// share one flock, which is released with the last of them.
+#[derive(Debug)]
+pub struct FileLock<'a> {
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    kind: LockKind,
+}
+
+impl<'a> FileLock<'a> {
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    pub fn unlock(self) -> crate::Result<()> {
+        let res = self.release();
+        std::mem::forget(self);
+        res.map_err(|e| crate::Error::new(crate::Operation::Unlock, e))
+    }
+
+    fn release(&self) -> Result<()> {
+        let mut held = self.table.held();
+        match &mut held.whole {
+            Some((_, count)) if *count > 1 => {
+                *count -= 1;
+                Ok(())
+            }
+            whole => {
+                *whole = None;
+                cvt(unsafe { libc::flock(self.fd.as_raw_fd(), libc::LOCK_UN) })
+            }
+        }
+    }
+}
+
+impl<'a> Drop for FileLock<'a> {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+#[derive(Debug)]
+pub struct RangeLock<'a> {
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    offset: u64,
+    len: u64,
+}
+
+impl<'a> RangeLock<'a> {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // `None` when the lock extends to the end of the file, however far that grows.
+    pub fn end(&self) -> Option<u64> {
+        (self.len != 0).then(|| self.offset + self.len)
+    }
+}
+
+impl<'a> Drop for RangeLock<'a> {
+    fn drop(&mut self) {
+        let mut held = self.table.held();
+        let _ = set_range_lock(
+            self.fd.as_raw_fd(),
+            libc::F_OFD_SETLK,
+            libc::F_UNLCK,
+            self.offset,
+            self.len,
+        );
+        held.ranges
+            .retain(|&range| range != (self.offset, self.len));
+    }
+}
+
+fn cvt(ret: libc::c_int) -> Result<()> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn retry_eintr(mut f: impl FnMut() -> Result<()>) -> Result<()> {
+    loop {
+        match f() {
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::Interrupted) => continue,
+            res => return res,
+        }
+    }
+}
+
+fn flock_op(kind: LockKind) -> libc::c_int {
+    match kind {
+        LockKind::Shared => libc::LOCK_SH,
+        LockKind::Exclusive => libc::LOCK_EX,
+    }
+}
+
+fn range_lock_type(kind: LockKind) -> libc::c_int {
+    match kind {
+        LockKind::Shared => libc::F_RDLCK,
+        LockKind::Exclusive => libc::F_WRLCK,
+    }
+}
+
+fn set_range_lock(
+    fd: RawFd,
+    cmd: libc::c_int,
+    ty: libc::c_int,
+    offset: u64,
+    len: u64,
+) -> Result<()> {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = ty as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = offset as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    cvt(unsafe { libc::fcntl(fd, cmd, &lock) })
+}
+
+fn would_block(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES))
+        || matches!(e.kind(), std::io::ErrorKind::WouldBlock)
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+// Polls with non-blocking attempts instead of parking a thread in a blocking call. A blocked
+// flock or F_OFD_SETLKW cannot be interrupted, so after a cancellation it would still take the
+// lock later and the open file description would end up in a state nobody asked for.
+//
+// Polling is not fair: the kernel does not queue the attempts, so an exclusive waiter can be
+// starved indefinitely by shared holders that keep overlapping each other.
+async fn wait_for_lock(mut try_acquire: impl FnMut() -> Result<bool>) -> Result<()> {
+    let mut backoff = MIN_BACKOFF;
+    while !try_acquire()? {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    Ok(())
+}
+
+// The table stays locked across the non-blocking syscall, so two tasks locking through the same
+// file cannot both see it free.
+fn try_flock(fd: RawFd, table: &LockTable, kind: LockKind) -> Result<bool> {
+    let mut held = table.held();
+    match (&mut held.whole, kind) {
+        (Some((LockKind::Shared, count)), LockKind::Shared) => {
+            *count += 1;
+            return Ok(true);
+        }
+        (Some(_), _) => return Err(already_held()),
+        (None, _) => (),
+    }
+    match retry_eintr(|| cvt(unsafe { libc::flock(fd, flock_op(kind) | libc::LOCK_NB) })) {
+        Ok(()) => {
+            held.whole = Some((kind, 1));
+            Ok(true)
+        }
+        Err(e) if would_block(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn try_range_lock(
+    fd: RawFd,
+    table: &LockTable,
+    offset: u64,
+    len: u64,
+    kind: LockKind,
+) -> Result<bool> {
+    let mut held = table.held();
+    if held
+        .ranges
+        .iter()
+        .any(|&range| overlaps(range, (offset, len)))
+    {
+        return Err(already_held());
+    }
+    match set_range_lock(fd, libc::F_OFD_SETLK, range_lock_type(kind), offset, len) {
+        Ok(()) => {
+            held.ranges.push((offset, len));
+            Ok(true)
+        }
+        Err(e) if would_block(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) async fn lock<'a>(
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    kind: LockKind,
+) -> Result<FileLock<'a>> {
+    wait_for_lock(|| try_flock(fd.as_raw_fd(), table, kind)).await?;
+    Ok(FileLock { fd, table, kind })
+}
+
+pub(crate) fn try_lock<'a>(
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    kind: LockKind,
+) -> Result<Option<FileLock<'a>>> {
+    Ok(try_flock(fd.as_raw_fd(), table, kind)?.then_some(FileLock { fd, table, kind }))
+}
+
+pub(crate) async fn lock_range<'a>(
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    offset: u64,
+    len: u64,
+    kind: LockKind,
+) -> Result<RangeLock<'a>> {
+    wait_for_lock(|| try_range_lock(fd.as_raw_fd(), table, offset, len, kind)).await?;
+    Ok(RangeLock {
+        fd,
+        table,
+        offset,
+        len,
+    })
+}
+
+pub(crate) fn try_lock_range<'a>(
+    fd: BorrowedFd<'a>,
+    table: &'a LockTable,
+    offset: u64,
+    len: u64,
+    kind: LockKind,
+) -> Result<Option<RangeLock<'a>>> {
+    Ok(
+        try_range_lock(fd.as_raw_fd(), table, offset, len, kind)?.then_some(RangeLock {
+            fd,
+            table,
+            offset,
+            len,
+        }),
+    )
+}
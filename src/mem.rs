@@ -0,0 +1,359 @@
+use crate::{BackendKind, File, FileIo, Metadata};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+struct State {
+    pages: BTreeMap<u64, Box<[u8]>>,
+    len: u64,
+    created: SystemTime,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+impl State {
+    fn new() -> Self {
+        let now = SystemTime::now();
+        Self {
+            pages: BTreeMap::new(),
+            len: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> usize {
+        self.accessed = SystemTime::now();
+        if pos >= self.len {
+            return 0;
+        }
+        let cnt = buf.len().min((self.len - pos) as usize);
+        let mut done = 0;
+        while done < cnt {
+            let at = pos + done as u64;
+            let (page, offset) = (at / PAGE_SIZE, (at % PAGE_SIZE) as usize);
+            let n = (cnt - done).min(PAGE_SIZE as usize - offset);
+            let dst = &mut buf[done..done + n];
+            match self.pages.get(&page) {
+                Some(data) => dst.copy_from_slice(&data[offset..offset + n]),
+                None => dst.fill(0),
+            }
+            done += n;
+        }
+        cnt
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let (page, offset) = (at / PAGE_SIZE, (at % PAGE_SIZE) as usize);
+            let n = (buf.len() - done).min(PAGE_SIZE as usize - offset);
+            let data = self
+                .pages
+                .entry(page)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+            data[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+            done += n;
+        }
+        self.len = self.len.max(pos + buf.len() as u64);
+        self.modified = SystemTime::now();
+        buf.len()
+    }
+
+    fn set_len(&mut self, size: u64) {
+        if size < self.len {
+            let first_dropped = size.div_ceil(PAGE_SIZE);
+            self.pages.split_off(&first_dropped);
+            let tail = (size % PAGE_SIZE) as usize;
+            if tail != 0 {
+                if let Some(data) = self.pages.get_mut(&(size / PAGE_SIZE)) {
+                    data[tail..].fill(0);
+                }
+            }
+        }
+        self.len = size;
+        self.modified = SystemTime::now();
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::new(self.len)
+            .with_created(self.created)
+            .with_modified(self.modified)
+            .with_accessed(self.accessed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemFile {
+    state: Arc<Mutex<State>>,
+    latency: Duration,
+}
+
+impl MemFile {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::new())),
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    pub fn allocated_bytes(&self) -> u64 {
+        self.state().pages.len() as u64 * PAGE_SIZE
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The whole file, for tests checking what went through a wrapper.
+    #[cfg(test)]
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        let mut state = self.state();
+        let mut buf = vec![0; state.len as usize];
+        state.read_at(0, &mut buf);
+        buf
+    }
+
+    async fn delay(&self) {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+    }
+}
+
+impl Default for MemFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileIo for MemFile {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.delay().await;
+        Ok(self.state().read_at(pos, buf))
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        self.delay().await;
+        Ok(self.state().write_at(pos, buf))
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.delay().await;
+        Ok(())
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.delay().await;
+        Ok(())
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.delay().await;
+        self.state().set_len(size);
+        Ok(())
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        self.delay().await;
+        Ok(self.state().metadata())
+    }
+
+    fn kind(&self) -> BackendKind {
+        BackendKind::Memory
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<HashMap<PathBuf, MemFile>>>,
+    latency: Duration,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<PathBuf, MemFile>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn create(&self, path: impl AsRef<Path>) -> File<MemFile> {
+        let file = MemFile::new().with_latency(self.latency);
+        self.files()
+            .insert(path.as_ref().to_path_buf(), file.clone());
+        File::from_io(file)
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> crate::Result<File<MemFile>> {
+        match self.files().get(path.as_ref()) {
+            Some(file) => Ok(File::from_io(file.clone())),
+            None => Err(crate::Error::new(
+                crate::Operation::Open,
+                std::io::ErrorKind::NotFound.into(),
+            )
+            .with_backend(BackendKind::Memory)
+            .with_path(path.as_ref())),
+        }
+    }
+
+    pub fn exists(&self, path: impl AsRef<Path>) -> bool {
+        self.files().contains_key(path.as_ref())
+    }
+
+    pub fn remove(&self, path: impl AsRef<Path>) -> bool {
+        self.files().remove(path.as_ref()).is_some()
+    }
+
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> bool {
+        let mut files = self.files();
+        match files.remove(from.as_ref()) {
+            Some(file) => {
+                files.insert(to.as_ref().to_path_buf(), file);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self.files().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn holes_read_as_zeroes_without_allocating() {
+        let file = MemFile::new();
+        file.write_at(3 * PAGE_SIZE + 10, b"tail").await.unwrap();
+        assert_eq!(file.metadata().await.unwrap().len(), 3 * PAGE_SIZE + 14);
+        assert_eq!(file.allocated_bytes(), PAGE_SIZE);
+        let mut buf = vec![1; PAGE_SIZE as usize * 4];
+        let cnt = file.read_at(0, &mut buf).await.unwrap();
+        assert_eq!(cnt as u64, 3 * PAGE_SIZE + 14);
+        assert!(buf[..cnt - 4].iter().all(|&b| b == 0));
+        assert_eq!(&buf[cnt - 4..cnt], b"tail");
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_span_pages() {
+        let file = MemFile::new();
+        let data: Vec<u8> = (0..PAGE_SIZE as usize + 100).map(|i| i as u8).collect();
+        file.write_at(PAGE_SIZE - 50, &data).await.unwrap();
+        assert_eq!(file.allocated_bytes(), 3 * PAGE_SIZE);
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            file.read_at(PAGE_SIZE - 50, &mut buf).await.unwrap(),
+            data.len()
+        );
+        assert_eq!(buf, data);
+        assert_eq!(file.read_at(10 * PAGE_SIZE, &mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn shrinking_zeroes_the_rest_of_the_last_page() {
+        let file = MemFile::new();
+        file.write_at(0, &[7; 2 * PAGE_SIZE as usize])
+            .await
+            .unwrap();
+        file.set_len(100).await.unwrap();
+        assert_eq!(file.allocated_bytes(), PAGE_SIZE);
+        file.set_len(2 * PAGE_SIZE).await.unwrap();
+        let contents = file.contents();
+        assert!(contents[..100].iter().all(|&b| b == 7));
+        assert!(contents[100..].iter().all(|&b| b == 0));
+    }
+
+    #[tokio::test]
+    async fn shrinking_to_a_page_boundary_drops_whole_pages() {
+        let file = MemFile::new();
+        file.write_at(0, &[7; 3 * PAGE_SIZE as usize])
+            .await
+            .unwrap();
+        file.set_len(PAGE_SIZE).await.unwrap();
+        assert_eq!(file.allocated_bytes(), PAGE_SIZE);
+        assert_eq!(file.contents(), vec![7; PAGE_SIZE as usize]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_operation_waits_for_the_latency() {
+        let latency = Duration::from_millis(5);
+        let file = MemFile::new().with_latency(latency);
+        let start = Instant::now();
+        file.write_at(0, b"abc").await.unwrap();
+        file.read_at(0, &mut [0; 3]).await.unwrap();
+        file.sync_all().await.unwrap();
+        file.set_len(1).await.unwrap();
+        file.metadata().await.unwrap();
+        assert_eq!(Instant::now() - start, latency * 5);
+    }
+
+    #[tokio::test]
+    async fn fs_files_share_state_between_handles() {
+        let fs = MemFs::new();
+        let created = fs.create("a");
+        created.write_at(0, b"hello").await.unwrap();
+        let opened = fs.open("a").unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(opened.read_at(0, &mut buf).await.unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        let err = fs.open("missing").err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.path(), Some(Path::new("missing")));
+    }
+
+    #[tokio::test]
+    async fn fs_rename_and_remove() {
+        let fs = MemFs::new();
+        fs.create("b").write_at(0, b"x").await.unwrap();
+        fs.create("a");
+        assert_eq!(fs.paths(), [PathBuf::from("a"), PathBuf::from("b")]);
+        assert!(fs.rename("b", "c"));
+        assert!(!fs.exists("b"));
+        assert_eq!(fs.open("c").unwrap().metadata().await.unwrap().len(), 1);
+        assert!(!fs.rename("b", "d"));
+        assert!(fs.remove("a"));
+        assert!(!fs.remove("a"));
+        assert_eq!(fs.paths(), [PathBuf::from("c")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fs_latency_applies_to_its_files() {
+        let fs = MemFs::new().with_latency(Duration::from_millis(3));
+        let file = fs.create("a");
+        let start = Instant::now();
+        file.write_at(0, b"abc").await.unwrap();
+        assert_eq!(Instant::now() - start, Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,164 @@
+// Every operation on `File` is recorded, including the Linux-specific ones. Opening a file and
+// accesses through a memory map happen outside any backend and are not.
+
+use crate::{BackendKind, Operation};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+
+const BUCKETS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpEvent {
+    pub backend: BackendKind,
+    pub op: Operation,
+    pub latency: Duration,
+    pub bytes: Option<u64>,
+    pub ok: bool,
+}
+
+pub trait Recorder: Send + Sync {
+    fn record(&self, event: &OpEvent);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn bucket(latency: Duration) -> usize {
+        let micros = latency.as_micros().max(1);
+        (u128::BITS - 1 - micros.leading_zeros()).min(BUCKETS as u32 - 1) as usize
+    }
+
+    fn add(&mut self, latency: Duration) {
+        self.buckets[Self::bucket(latency)] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((self.sum.as_nanos() / n as u128) as u64),
+        }
+    }
+
+    // Bucket `i` counts latencies in `[2^i, 2^(i + 1))` microseconds.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if n != 0 && seen >= target {
+                return Duration::from_micros(1 << (i + 1));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpStats {
+    pub backend: BackendKind,
+    pub op: Operation,
+    pub count: u64,
+    pub errors: u64,
+    pub bytes: u64,
+    pub latency: Histogram,
+}
+
+type Key = (BackendKind, Operation);
+
+fn registry() -> &'static Mutex<HashMap<Key, OpStats>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<Key, OpStats>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn recorder() -> &'static RwLock<Option<Arc<dyn Recorder>>> {
+    static RECORDER: OnceLock<RwLock<Option<Arc<dyn Recorder>>>> = OnceLock::new();
+    RECORDER.get_or_init(Default::default)
+}
+
+pub fn set_recorder(recorder_impl: Option<Arc<dyn Recorder>>) {
+    *recorder().write().unwrap_or_else(|e| e.into_inner()) = recorder_impl;
+}
+
+pub fn snapshot() -> Vec<OpStats> {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    registry.values().cloned().collect()
+}
+
+pub fn reset() {
+    registry().lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+pub(crate) fn record(event: OpEvent) {
+    {
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+        let stats = registry
+            .entry((event.backend, event.op))
+            .or_insert_with(|| OpStats {
+                backend: event.backend,
+                op: event.op,
+                count: 0,
+                errors: 0,
+                bytes: 0,
+                latency: Histogram::default(),
+            });
+        stats.count += 1;
+        if !event.ok {
+            stats.errors += 1;
+        }
+        stats.bytes += event.bytes.unwrap_or(0);
+        stats.latency.add(event.latency);
+    }
+    let recorder = recorder().read().unwrap_or_else(|e| e.into_inner()).clone();
+    if let Some(recorder) = recorder {
+        recorder.record(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_survives_counts_beyond_u32() {
+        let mut hist = Histogram::default();
+        hist.add(Duration::from_micros(3));
+        hist.add(Duration::from_micros(5));
+        assert_eq!(hist.mean(), Duration::from_micros(4));
+
+        hist.count = 1 << 32;
+        hist.sum = Duration::from_secs(1 << 32);
+        assert_eq!(hist.mean(), Duration::from_secs(1));
+        hist.count = 3 << 32;
+        assert_eq!(hist.mean(), Duration::from_nanos(333_333_333));
+    }
+
+    #[test]
+    fn quantile_reports_bucket_upper_bound() {
+        let mut hist = Histogram::default();
+        for micros in [1, 2, 3, 100] {
+            hist.add(Duration::from_micros(micros));
+        }
+        assert_eq!(hist.quantile(0.5), Duration::from_micros(4));
+        assert_eq!(hist.quantile(1.0), Duration::from_micros(128));
+    }
+}
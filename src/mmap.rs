@@ -0,0 +1,188 @@
+use crate::{unix, Error, Operation};
+use std::io::Result;
+use std::ops::{Deref, DerefMut, Range};
+use std::os::fd::RawFd;
+
+#[derive(Debug)]
+struct RawMap {
+    base: *mut libc::c_void,
+    map_len: usize,
+    delta: usize,
+    len: usize,
+}
+
+unsafe impl Send for RawMap {}
+
+unsafe impl Sync for RawMap {}
+
+impl RawMap {
+    fn new(fd: RawFd, offset: u64, len: usize, prot: libc::c_int) -> Result<Self> {
+        if len == 0 {
+            return Ok(Self {
+                base: std::ptr::NonNull::<u8>::dangling().as_ptr() as *mut libc::c_void,
+                map_len: 0,
+                delta: 0,
+                len: 0,
+            });
+        }
+        let delta = (offset % page_size() as u64) as usize;
+        let map_len = len + delta;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                prot,
+                libc::MAP_SHARED,
+                fd,
+                (offset - delta as u64) as libc::off_t,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            base,
+            map_len,
+            delta,
+            len,
+        })
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        unsafe { (self.base as *mut u8).add(self.delta) }
+    }
+
+    // Returns the page aligned address and length covering `range` of the mapping.
+    fn pages(&self, range: Range<usize>) -> (usize, usize) {
+        assert!(
+            range.start <= range.end && range.end <= self.len,
+            "range out of bounds of mapping"
+        );
+        let start = self.base as usize + self.delta + range.start;
+        let aligned = self.base as usize + (self.delta + range.start) / page_size() * page_size();
+        (aligned, range.end - range.start + (start - aligned))
+    }
+
+    async fn prefetch(&self, range: Range<usize>) -> crate::Result<()> {
+        if self.map_len == 0 || range.is_empty() {
+            return Ok(());
+        }
+        let (start, end) = (range.start as u64, range.end as u64);
+        let (addr, len) = self.pages(range);
+        unix::asyncify(move || {
+            if unsafe { libc::madvise(addr as *mut libc::c_void, len, libc::MADV_WILLNEED) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+        .map_err(|e| Error::new(Operation::Prefetch, e).with_range(start, end - start))
+    }
+
+    async fn flush(&self, range: Range<usize>) -> crate::Result<()> {
+        if self.map_len == 0 || range.is_empty() {
+            return Ok(());
+        }
+        let (start, end) = (range.start as u64, range.end as u64);
+        let (addr, len) = self.pages(range);
+        unix::asyncify(move || {
+            if unsafe { libc::msync(addr as *mut libc::c_void, len, libc::MS_SYNC) } < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+        .await
+        .map_err(|e| Error::new(Operation::Flush, e).with_range(start, end - start))
+    }
+}
+
+impl Drop for RawMap {
+    fn drop(&mut self) {
+        if self.map_len != 0 {
+            unsafe {
+                libc::munmap(self.base, self.map_len);
+            }
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[derive(Debug)]
+pub struct Mmap(RawMap);
+
+impl Mmap {
+    pub(crate) fn new(fd: RawFd, offset: u64, len: usize) -> Result<Self> {
+        Ok(Self(RawMap::new(fd, offset, len, libc::PROT_READ)?))
+    }
+
+    pub async fn prefetch(&self, range: Range<usize>) -> crate::Result<()> {
+        self.0.prefetch(range).await
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.0.ptr(), self.0.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct MmapMut(RawMap);
+
+impl MmapMut {
+    pub(crate) fn new(fd: RawFd, offset: u64, len: usize) -> Result<Self> {
+        Ok(Self(RawMap::new(
+            fd,
+            offset,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+        )?))
+    }
+
+    pub async fn prefetch(&self, range: Range<usize>) -> crate::Result<()> {
+        self.0.prefetch(range).await
+    }
+
+    pub async fn flush_async(&self, range: Range<usize>) -> crate::Result<()> {
+        self.0.flush(range).await
+    }
+}
+
+impl Deref for MmapMut {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.0.ptr(), self.0.len) }
+    }
+}
+
+impl DerefMut for MmapMut {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.0.ptr(), self.0.len) }
+    }
+}
+
+impl AsRef<[u8]> for MmapMut {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for MmapMut {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
@@ -1,5 +1,4 @@
-use crate::File;
-use std::io::Result;
+use crate::{File, Result};
 use std::path::Path;
 
 #[derive(Debug, Clone, Default)]
@@ -0,0 +1,255 @@
+use crate::buf::Limit;
+use crate::{File, FileIo, Result};
+use bytes::Bytes;
+use std::future::{poll_fn, Future};
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::Poll;
+
+#[derive(Debug, Clone)]
+pub struct ReadRangesOptions {
+    gap: u64,
+    max_len: u64,
+    concurrency: usize,
+}
+
+impl Default for ReadRangesOptions {
+    fn default() -> Self {
+        Self {
+            gap: 64 * 1024,
+            max_len: 16 * 1024 * 1024,
+            concurrency: 8,
+        }
+    }
+}
+
+impl ReadRangesOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Ranges whose distance is at most `gap` bytes are served by one read.
+    pub fn gap(&mut self, gap: u64) -> &mut Self {
+        self.gap = gap;
+        self
+    }
+
+    // Merging stops once a read would grow beyond `max_len`; a single larger range is still
+    // read in one piece.
+    pub fn max_len(&mut self, max_len: u64) -> &mut Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+struct Merged {
+    range: Range<u64>,
+    members: Vec<usize>,
+}
+
+fn plan(ranges: &[Range<u64>], options: &ReadRangesOptions) -> Vec<Merged> {
+    let mut order: Vec<usize> = (0..ranges.len())
+        .filter(|&i| ranges[i].start < ranges[i].end)
+        .collect();
+    order.sort_by_key(|&i| (ranges[i].start, ranges[i].end));
+    let mut merged: Vec<Merged> = Vec::new();
+    for i in order {
+        let range = &ranges[i];
+        if let Some(last) = merged.last_mut() {
+            let end = last.range.end.max(range.end);
+            if range.start <= last.range.end.saturating_add(options.gap)
+                && end - last.range.start <= options.max_len
+            {
+                last.range.end = end;
+                last.members.push(i);
+                continue;
+            }
+        }
+        merged.push(Merged {
+            range: range.clone(),
+            members: vec![i],
+        });
+    }
+    merged
+}
+
+// The buffer at least doubles with every read instead of being allocated for the whole range up
+// front, so a range that reaches far past the end of the file only costs what is actually there.
+const FIRST_READ: usize = 64 * 1024;
+
+// The buffer is owned by each read while it runs, so dropping this future part way, as
+// `join_bounded` and a dropped `Chunks` do, cannot free memory a blocking read still fills.
+pub(crate) async fn read_range<B: FileIo>(file: &File<B>, range: Range<u64>) -> Result<Bytes> {
+    let len = usize::try_from(range.end - range.start).unwrap_or(usize::MAX);
+    let mut buf = Vec::new();
+    while buf.len() < len {
+        let want = (len - buf.len()).min(buf.len().max(FIRST_READ));
+        buf.reserve_exact(want);
+        let pos = range.start + buf.len() as u64;
+        let (res, limited) = file.read_owned_at(pos, Limit::new(buf, want)).await;
+        buf = limited.into_inner();
+        if res? == 0 {
+            break;
+        }
+    }
+    Ok(buf.into())
+}
+
+// Runs `futs` with at most `limit` of them in flight and returns their outputs in order. The
+// first error wins and drops everything still running.
+pub(crate) async fn join_bounded<F, T>(
+    futs: impl IntoIterator<Item = F>,
+    limit: usize,
+) -> Result<Vec<T>>
+where
+    F: Future<Output = Result<T>>,
+{
+    let mut pending = futs.into_iter().enumerate();
+    let mut in_flight: Vec<(usize, Pin<Box<F>>)> = Vec::new();
+    let mut out: Vec<Option<T>> = Vec::new();
+    poll_fn(|cx| loop {
+        while in_flight.len() < limit.max(1) {
+            match pending.next() {
+                Some((i, fut)) => {
+                    out.push(None);
+                    in_flight.push((i, Box::pin(fut)));
+                }
+                None => break,
+            }
+        }
+        if in_flight.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let mut progressed = false;
+        let mut j = 0;
+        while j < in_flight.len() {
+            match in_flight[j].1.as_mut().poll(cx) {
+                Poll::Ready(Ok(val)) => {
+                    out[in_flight[j].0] = Some(val);
+                    in_flight.swap_remove(j);
+                    progressed = true;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => j += 1,
+            }
+        }
+        if !progressed {
+            return Poll::Pending;
+        }
+    })
+    .await?;
+    Ok(out
+        .into_iter()
+        .map(|val| val.expect("every future completed"))
+        .collect())
+}
+
+pub(crate) async fn read_ranges<B: FileIo>(
+    file: &File<B>,
+    ranges: &[Range<u64>],
+    options: &ReadRangesOptions,
+) -> Result<Vec<Bytes>> {
+    let merged = plan(ranges, options);
+    let reads = merged.iter().map(|m| read_range(file, m.range.clone()));
+    let data = join_bounded(reads, options.concurrency).await?;
+    let mut out = vec![Bytes::new(); ranges.len()];
+    for (m, data) in merged.iter().zip(data) {
+        for &i in &m.members {
+            // Past the end of the file a range comes back short, just like `read_at`.
+            let len = data.len() as u64;
+            let start = (ranges[i].start - m.range.start).min(len);
+            let end = (ranges[i].end - m.range.start).min(len);
+            out[i] = data.slice(start as usize..end as usize);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use std::time::Duration;
+
+    fn merged(ranges: &[Range<u64>], options: &ReadRangesOptions) -> Vec<(Range<u64>, Vec<usize>)> {
+        plan(ranges, options)
+            .into_iter()
+            .map(|m| (m.range, m.members))
+            .collect()
+    }
+
+    #[test]
+    fn nearby_ranges_are_merged() {
+        let mut options = ReadRangesOptions::new();
+        options.gap(100);
+        let ranges = [1000..1010, 0..10, 50..60, 5..8, 70..70, 161..170];
+        assert_eq!(
+            merged(&ranges, &options),
+            vec![
+                (0..60, vec![1, 3, 2]),
+                (161..170, vec![5]),
+                (1000..1010, vec![0])
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_stops_at_max_len() {
+        let mut options = ReadRangesOptions::new();
+        options.gap(100).max_len(30);
+        let ranges = [0..10, 20..30, 40..50, 45..200];
+        assert_eq!(
+            merged(&ranges, &options),
+            vec![(0..30, vec![0, 1]), (40..50, vec![2]), (45..200, vec![3])]
+        );
+    }
+
+    #[tokio::test]
+    async fn results_come_back_in_request_order() {
+        let mem = MemFile::new();
+        let data: Vec<u8> = (0..=255).collect();
+        mem.write_at(0, &data).await.unwrap();
+        let file = File::from_io(mem);
+        let ranges = [200..210, 0..4, 2..6, 250..300, 300..310, 7..7];
+        let out = file.read_ranges(&ranges).await.unwrap();
+        assert_eq!(&out[0][..], &data[200..210]);
+        assert_eq!(&out[1][..], &data[0..4]);
+        assert_eq!(&out[2][..], &data[2..6]);
+        // Past the end of the file ranges come back short or empty.
+        assert_eq!(&out[3][..], &data[250..]);
+        assert!(out[4].is_empty());
+        assert!(out[5].is_empty());
+    }
+
+    #[tokio::test]
+    async fn ranges_far_past_eof_do_not_allocate_their_length() {
+        let mem = MemFile::new();
+        mem.write_at(0, &[1; 100_000]).await.unwrap();
+        let file = File::from_io(mem);
+        let out = file
+            .read_ranges(&[0..1 << 40, 1 << 50..(1 << 50) + 10])
+            .await
+            .unwrap();
+        assert_eq!(&out[0][..], &[1; 100_000][..]);
+        assert!(out[1].is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_are_bounded_by_concurrency() {
+        let mem = MemFile::new().with_latency(Duration::from_millis(10));
+        mem.write_at(0, &[1; 4096]).await.unwrap();
+        let file = File::from_io(mem);
+        let ranges: Vec<_> = (0..4).map(|i| i * 1024..i * 1024 + 10).collect();
+        let mut options = ReadRangesOptions::new();
+        options.gap(0).concurrency(2);
+        let start = tokio::time::Instant::now();
+        let out = file.read_ranges_with(&ranges, &options).await.unwrap();
+        assert_eq!(out.len(), 4);
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{BackendKind, File, FileIo, IoBuf, IoBufMut, Metadata, Native};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+const STRIDE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IoClass {
+    Foreground,
+    Background,
+    Sync,
+}
+
+impl IoClass {
+    const ALL: [IoClass; 3] = [IoClass::Foreground, IoClass::Background, IoClass::Sync];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoPriority {
+    // Encoded the way `ioprio_set(2)` expects it.
+    pub fn to_raw(self) -> u16 {
+        const CLASS_SHIFT: u16 = 13;
+        match self {
+            IoPriority::Realtime(level) => (1 << CLASS_SHIFT) | u16::from(level.min(7)),
+            IoPriority::BestEffort(level) => (2 << CLASS_SHIFT) | u16::from(level.min(7)),
+            IoPriority::Idle => 3 << CLASS_SHIFT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClassConfig {
+    weight: u32,
+    limit: Option<usize>,
+    ioprio: Option<IoPriority>,
+}
+
+#[derive(Debug, Default)]
+struct ClassState {
+    in_flight: usize,
+    pass: u64,
+    queue: VecDeque<oneshot::Sender<Permit>>,
+}
+
+#[derive(Debug)]
+struct State {
+    max_in_flight: usize,
+    in_flight: usize,
+    vtime: u64,
+    config: [ClassConfig; 3],
+    classes: [ClassState; 3],
+}
+
+impl State {
+    fn eligible(&self, class: IoClass) -> bool {
+        let i = class.index();
+        self.in_flight < self.max_in_flight
+            && self.config[i]
+                .limit
+                .is_none_or(|limit| self.classes[i].in_flight < limit)
+    }
+
+    // Stride scheduling: every grant advances the class by `STRIDE / weight`, and the class that
+    // is furthest behind goes next, so under contention each class gets its weighted share.
+    fn next(&mut self) -> Option<IoClass> {
+        IoClass::ALL
+            .into_iter()
+            .filter(|&class| !self.classes[class.index()].queue.is_empty() && self.eligible(class))
+            .min_by_key(|&class| self.classes[class.index()].pass)
+    }
+
+    fn grant(&mut self, class: IoClass) {
+        let i = class.index();
+        self.in_flight += 1;
+        self.classes[i].in_flight += 1;
+        self.vtime = self.vtime.max(self.classes[i].pass);
+        self.classes[i].pass += STRIDE / u64::from(self.config[i].weight.max(1));
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IoScheduler(Arc<Inner>);
+
+impl IoScheduler {
+    pub fn new(max_in_flight: usize) -> Self {
+        let config = |weight| ClassConfig {
+            weight,
+            limit: None,
+            ioprio: None,
+        };
+        Self(Arc::new(Inner {
+            state: Mutex::new(State {
+                max_in_flight: max_in_flight.max(1),
+                in_flight: 0,
+                vtime: 0,
+                config: [config(8), config(1), config(4)],
+                classes: Default::default(),
+            }),
+        }))
+    }
+
+    pub fn with_weight(self, class: IoClass, weight: u32) -> Self {
+        self.0.state().config[class.index()].weight = weight.max(1);
+        self
+    }
+
+    pub fn with_limit(self, class: IoClass, limit: usize) -> Self {
+        self.0.state().config[class.index()].limit = Some(limit.max(1));
+        self
+    }
+
+    // Has the kernel's I/O scheduler see requests of `class` at `prio`. This only works for
+    // requests served by the thread-pool backend, whose worker thread is tagged with
+    // `ioprio_set(2)` for the duration of the call. io_uring submissions are not tagged, since
+    // rio does not expose the SQE `ioprio` field, so on that backend, and on every platform
+    // other than Linux, the setting has no effect and classes differ only in how this
+    // scheduler orders and limits them.
+    pub fn with_ioprio(self, class: IoClass, prio: IoPriority) -> Self {
+        self.0.state().config[class.index()].ioprio = Some(prio);
+        self
+    }
+
+    pub fn in_flight(&self, class: IoClass) -> usize {
+        self.0.state().classes[class.index()].in_flight
+    }
+
+    pub fn queued(&self, class: IoClass) -> usize {
+        self.0.state().classes[class.index()].queue.len()
+    }
+
+    pub fn attach<B: FileIo>(&self, file: File<B>, class: IoClass) -> File<ScheduledFile<B>> {
+        File::from_io(ScheduledFile::new(file, self.clone(), class))
+    }
+
+    pub async fn run<F, T>(&self, class: IoClass, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let permit = self.acquire(class).await;
+        let res = with_ioprio(permit.ioprio, fut).await;
+        drop(permit);
+        res
+    }
+
+    async fn acquire(&self, class: IoClass) -> Permit {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.0.state();
+            let i = class.index();
+            if state.classes[i].queue.is_empty() {
+                // A class that was idle must not bank credit for the time it had nothing to do.
+                state.classes[i].pass = state.classes[i].pass.max(state.vtime);
+            }
+            state.classes[i].queue.push_back(tx);
+        }
+        self.dispatch();
+        // The sender is only dropped together with the scheduler state, which outlives us.
+        rx.await.expect("scheduler dropped a queued request")
+    }
+
+    fn dispatch(&self) {
+        let mut grants = Vec::new();
+        {
+            let mut state = self.0.state();
+            while let Some(class) = state.next() {
+                let tx = state.classes[class.index()]
+                    .queue
+                    .pop_front()
+                    .expect("eligible class has a waiter");
+                if tx.is_closed() {
+                    continue;
+                }
+                state.grant(class);
+                let ioprio = state.config[class.index()].ioprio;
+                grants.push((tx, class, ioprio));
+            }
+        }
+        // Permits are created outside the lock: if the waiter went away in the meantime the
+        // permit comes back and dropping it releases the slot again.
+        for (tx, class, ioprio) in grants {
+            let _ = tx.send(Permit {
+                sched: self.clone(),
+                class,
+                ioprio,
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Permit {
+    sched: IoScheduler,
+    class: IoClass,
+    ioprio: Option<IoPriority>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        {
+            let mut state = self.sched.0.state();
+            state.in_flight -= 1;
+            state.classes[self.class.index()].in_flight -= 1;
+        }
+        self.sched.dispatch();
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn with_ioprio<F: Future>(prio: Option<IoPriority>, fut: F) -> F::Output {
+    match prio {
+        Some(prio) => IOPRIO.scope(prio.to_raw(), fut).await,
+        None => fut.await,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn with_ioprio<F: Future>(_prio: Option<IoPriority>, fut: F) -> F::Output {
+    fut.await
+}
+
+// Picked up by `unix::asyncify`, so only requests served by the blocking pool are tagged.
+#[cfg(target_os = "linux")]
+tokio::task_local! {
+    pub(crate) static IOPRIO: u16;
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn current_ioprio() -> Option<u16> {
+    IOPRIO.try_with(|prio| *prio).ok()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn with_thread_ioprio<T>(prio: Option<u16>, f: impl FnOnce() -> T) -> T {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    let Some(prio) = prio else {
+        return f();
+    };
+    let old = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    let set = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            prio as libc::c_int,
+        )
+    };
+    let res = f();
+    if set == 0 && old >= 0 {
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, old) };
+    }
+    res
+}
+
+#[derive(Debug)]
+pub struct ScheduledFile<B = Native> {
+    inner: B,
+    sched: IoScheduler,
+    class: IoClass,
+}
+
+impl<B: FileIo> ScheduledFile<B> {
+    pub fn new(file: File<B>, sched: IoScheduler, class: IoClass) -> Self {
+        Self {
+            inner: file.into_inner(),
+            sched,
+            class,
+        }
+    }
+
+    pub fn into_file(self) -> File<Self> {
+        File::from_io(self)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn scheduler(&self) -> &IoScheduler {
+        &self.sched
+    }
+
+    pub fn class(&self) -> IoClass {
+        self.class
+    }
+
+    pub fn set_class(&mut self, class: IoClass) {
+        self.class = class;
+    }
+}
+
+impl<B: FileIo> FileIo for ScheduledFile<B> {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.sched
+            .run(self.class, self.inner.read_at(pos, buf))
+            .await
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        self.sched
+            .run(self.class, self.inner.write_at(pos, buf))
+            .await
+    }
+
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        self.sched
+            .run(self.class, self.inner.read_uninit_at(pos, buf))
+            .await
+    }
+
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        self.sched
+            .run(self.class, self.inner.read_owned_at(pos, buf))
+            .await
+    }
+
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        self.sched
+            .run(self.class, self.inner.write_owned_at(pos, buf))
+            .await
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.sched.run(IoClass::Sync, self.inner.sync_all()).await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.sched.run(IoClass::Sync, self.inner.sync_data()).await
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.sched.run(self.class, self.inner.set_len(size)).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        self.inner.metadata().await
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.inner.kind()
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        self.inner.descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn classes_get_their_weighted_share() {
+        let sched = IoScheduler::new(1)
+            .with_weight(IoClass::Foreground, 8)
+            .with_weight(IoClass::Background, 1);
+        let (release, blocked) = oneshot::channel::<()>();
+        let blocker = tokio::spawn({
+            let sched = sched.clone();
+            async move { sched.run(IoClass::Foreground, blocked).await }
+        });
+        settle().await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for _ in 0..20 {
+            for class in [IoClass::Foreground, IoClass::Background] {
+                let (sched, order) = (sched.clone(), order.clone());
+                tasks.push(tokio::spawn(async move {
+                    sched
+                        .run(class, async { order.lock().unwrap().push(class) })
+                        .await
+                }));
+            }
+        }
+        settle().await;
+        assert_eq!(sched.queued(IoClass::Foreground), 20);
+        assert_eq!(sched.queued(IoClass::Background), 20);
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = order.lock().unwrap();
+        let background = order[..18]
+            .iter()
+            .filter(|&&class| class == IoClass::Background)
+            .count();
+        assert_eq!(background, 2);
+    }
+
+    #[tokio::test]
+    async fn class_limit_caps_in_flight_requests() {
+        let sched = IoScheduler::new(4).with_limit(IoClass::Background, 1);
+        let mut releases = Vec::new();
+        let mut tasks = Vec::new();
+        for _ in 0..3 {
+            let (tx, rx) = oneshot::channel::<()>();
+            releases.push(tx);
+            let sched = sched.clone();
+            tasks.push(tokio::spawn(async move {
+                sched.run(IoClass::Background, rx).await
+            }));
+        }
+        settle().await;
+        assert_eq!(sched.in_flight(IoClass::Background), 1);
+        assert_eq!(sched.queued(IoClass::Background), 2);
+        for tx in releases {
+            let _ = tx.send(());
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(sched.in_flight(IoClass::Background), 0);
+    }
+
+    #[tokio::test]
+    async fn idle_class_does_not_bank_credit() {
+        let sched = IoScheduler::new(1)
+            .with_weight(IoClass::Foreground, 1)
+            .with_weight(IoClass::Background, 1);
+        for _ in 0..10 {
+            sched.run(IoClass::Foreground, async {}).await;
+        }
+        let (release, blocked) = oneshot::channel::<()>();
+        let blocker = tokio::spawn({
+            let sched = sched.clone();
+            async move { sched.run(IoClass::Foreground, blocked).await }
+        });
+        settle().await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            for class in [IoClass::Background, IoClass::Foreground] {
+                let (sched, order) = (sched.clone(), order.clone());
+                tasks.push(tokio::spawn(async move {
+                    sched
+                        .run(class, async { order.lock().unwrap().push(class) })
+                        .await
+                }));
+            }
+        }
+        settle().await;
+        release.send(()).unwrap();
+        blocker.await.unwrap().unwrap();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Background was idle while foreground ran alone, so it starts level instead of
+        // getting ten grants in a row.
+        let order = order.lock().unwrap();
+        let background = order[..4]
+            .iter()
+            .filter(|&&class| class == IoClass::Background)
+            .count();
+        assert_eq!(background, 2);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::{Error, File, FileIo, Native, Operation, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::ops::Range;
+use std::pin::{pin, Pin};
+use std::task::{ready, Context, Poll};
+
+type WriteChunk<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+type SyncData<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+// The chunk is handed to the backend for every write, so dropping the sink with writes in flight
+// cannot free data a blocking write is still reading.
+async fn write_all_at<B: FileIo>(file: &File<B>, mut pos: u64, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        let (res, buf) = file.write_owned_at(pos, data).await;
+        data = buf;
+        match res? {
+            0 => {
+                return Err(
+                    Error::new(Operation::WriteAt, std::io::ErrorKind::WriteZero.into())
+                        .with_backend(file.backend())
+                        .with_range(pos, data.len() as u64),
+                )
+            }
+            n => {
+                pos += n as u64;
+                data = data.slice(n..);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct WriteSink<'a, B = Native> {
+    file: &'a File<B>,
+    offset: u64,
+    max_in_flight: usize,
+    in_flight: Vec<(WriteChunk<'a>, Range<u64>)>,
+    // Completed writes that are not yet contiguous with `committed`, keyed by start.
+    finished: BTreeMap<u64, u64>,
+    committed: u64,
+    durable: u64,
+    error: Option<Error>,
+    failed: bool,
+    sync: Option<SyncData<'a>>,
+}
+
+impl<'a, B: FileIo> WriteSink<'a, B> {
+    pub(crate) fn new(file: &'a File<B>, start: u64, max_in_flight: usize) -> Self {
+        Self {
+            file,
+            offset: start,
+            max_in_flight: max_in_flight.max(1),
+            in_flight: Vec::new(),
+            finished: BTreeMap::new(),
+            committed: start,
+            durable: start,
+            error: None,
+            failed: false,
+            sync: None,
+        }
+    }
+
+    // Offset the next item will be written at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Everything below this offset has been written, though not necessarily synced.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    // Everything below this offset was written and synced; this is where a resumed upload
+    // should start.
+    pub fn durable(&self) -> u64 {
+        self.durable
+    }
+
+    fn poll_writes(&mut self, cx: &mut Context<'_>) {
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            match self.in_flight[i].0.as_mut().poll(cx) {
+                Poll::Ready(res) => {
+                    let (_, range) = self.in_flight.swap_remove(i);
+                    match res {
+                        Ok(()) => {
+                            self.finished.insert(range.start, range.end);
+                        }
+                        Err(e) => {
+                            self.error.get_or_insert(e);
+                        }
+                    }
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        while let Some(end) = self.finished.remove(&self.committed) {
+            self.committed = end;
+        }
+    }
+
+    fn poisoned(&self) -> Error {
+        Error::new(
+            Operation::WriteAt,
+            std::io::Error::other("write sink failed earlier"),
+        )
+        .with_backend(self.file.backend())
+    }
+
+    // Once a write failed the remaining ones are still driven to completion, so `committed` is
+    // accurate by the time the error is reported.
+    fn poll_error(&mut self) -> Option<Poll<Result<()>>> {
+        if self.failed {
+            return Some(Poll::Ready(Err(self.poisoned())));
+        }
+        self.error.as_ref()?;
+        if !self.in_flight.is_empty() {
+            return Some(Poll::Pending);
+        }
+        self.failed = true;
+        self.error.take().map(|e| Poll::Ready(Err(e)))
+    }
+
+    fn poll_sync(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let file = self.file;
+        let committed = self.committed;
+        let sync = self.sync.get_or_insert_with(|| Box::pin(file.sync_data()));
+        let res = ready!(sync.as_mut().poll(cx));
+        self.sync = None;
+        if res.is_ok() {
+            self.durable = committed;
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<B: FileIo> Sink<Bytes> for WriteSink<'_, B> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_writes(cx);
+        if let Some(poll) = self.poll_error() {
+            return poll;
+        }
+        if self.in_flight.len() < self.max_in_flight {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<()> {
+        if self.failed || self.error.is_some() {
+            return Err(self.poisoned());
+        }
+        if item.is_empty() {
+            return Ok(());
+        }
+        let pos = self.offset;
+        let end = pos + item.len() as u64;
+        let write = Box::pin(write_all_at(self.file, pos, item));
+        self.in_flight.push((write, pos..end));
+        self.offset = end;
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_writes(cx);
+        if let Some(poll) = self.poll_error() {
+            return poll;
+        }
+        if self.in_flight.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.poll_sync(cx)
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteStreamError {
+    error: Error,
+    durable: u64,
+}
+
+impl WriteStreamError {
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> Error {
+        self.error
+    }
+
+    pub fn durable(&self) -> u64 {
+        self.durable
+    }
+}
+
+impl fmt::Display for WriteStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (durable up to offset {})", self.error, self.durable)
+    }
+}
+
+impl std::error::Error for WriteStreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<WriteStreamError> for Error {
+    fn from(e: WriteStreamError) -> Self {
+        e.error
+    }
+}
+
+pub(crate) async fn write_stream<B, S>(
+    file: &File<B>,
+    start: u64,
+    stream: S,
+    max_in_flight: usize,
+) -> std::result::Result<u64, WriteStreamError>
+where
+    B: FileIo,
+    S: Stream<Item = Bytes>,
+{
+    let mut stream = pin!(stream);
+    let mut sink = WriteSink::new(file, start, max_in_flight);
+    let res = poll_fn(|cx| loop {
+        ready!(Pin::new(&mut sink).poll_ready(cx))?;
+        match stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(data)) => Pin::new(&mut sink).start_send(data)?,
+            Poll::Ready(None) => return Pin::new(&mut sink).poll_close(cx),
+            // Keep the writes moving while the producer is idle.
+            Poll::Pending => {
+                ready!(Pin::new(&mut sink).poll_flush(cx))?;
+                return Poll::Pending;
+            }
+        }
+    })
+    .await;
+    match res {
+        Ok(()) => Ok(sink.durable()),
+        Err(error) => {
+            // Make whatever did land durable so the caller can resume from there.
+            if sink.committed() > sink.durable() {
+                let _ = poll_fn(|cx| sink.poll_sync(cx)).await;
+            }
+            Err(WriteStreamError {
+                error,
+                durable: sink.durable(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemFile;
+    use crate::{IoBuf, Metadata};
+    use std::time::Duration;
+
+    // Earlier offsets take longer, so writes finish in the reverse of the order they were sent.
+    // Only owned writes are served.
+    struct Reversed {
+        mem: MemFile,
+        fail_at: Option<u64>,
+    }
+
+    impl FileIo for Reversed {
+        async fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.mem.read_at(pos, buf).await
+        }
+
+        async fn write_at(&self, _pos: u64, _buf: &[u8]) -> std::io::Result<usize> {
+            panic!("the sink must not lend its chunks to write_at");
+        }
+
+        async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (std::io::Result<usize>, T) {
+            tokio::time::sleep(Duration::from_millis(50 - pos)).await;
+            if self.fail_at == Some(pos) {
+                return (Err(std::io::Error::other("injected")), buf);
+            }
+            self.mem.write_owned_at(pos, buf).await
+        }
+
+        async fn sync_all(&self) -> std::io::Result<()> {
+            self.mem.sync_all().await
+        }
+
+        async fn sync_data(&self) -> std::io::Result<()> {
+            self.mem.sync_data().await
+        }
+
+        async fn set_len(&self, size: u64) -> std::io::Result<()> {
+            self.mem.set_len(size).await
+        }
+
+        async fn metadata(&self) -> std::io::Result<Metadata> {
+            self.mem.metadata().await
+        }
+    }
+
+    struct Iter(std::vec::IntoIter<Bytes>);
+
+    impl Stream for Iter {
+        type Item = Bytes;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    fn chunks() -> Vec<Bytes> {
+        (0..3u8).map(|i| Bytes::from(vec![i; 10])).collect()
+    }
+
+    fn reversed(fail_at: Option<u64>) -> (MemFile, File<Reversed>) {
+        let mem = MemFile::new();
+        let file = File::from_io(Reversed {
+            mem: mem.clone(),
+            fail_at,
+        });
+        (mem, file)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn committed_only_advances_over_contiguous_writes() {
+        let (mem, file) = reversed(None);
+        let mut sink = file.write_sink(0, 3);
+        for chunk in chunks() {
+            poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+                .await
+                .unwrap();
+            Pin::new(&mut sink).start_send(chunk).unwrap();
+        }
+        assert_eq!(sink.offset(), 30);
+        let flush = poll_fn(|cx| Pin::new(&mut sink).poll_flush(cx));
+        assert!(tokio::time::timeout(Duration::from_millis(45), flush)
+            .await
+            .is_err());
+        // The last two chunks landed, but the first is still in flight.
+        assert_eq!(sink.committed(), 0);
+        poll_fn(|cx| Pin::new(&mut sink).poll_close(cx))
+            .await
+            .unwrap();
+        assert_eq!((sink.committed(), sink.durable()), (30, 30));
+        let mut buf = [0; 30];
+        assert_eq!(mem.read_at(0, &mut buf).await.unwrap(), 30);
+        assert_eq!(&buf[..], &[[0; 10], [1; 10], [2; 10]].concat()[..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_stream_reports_the_durable_prefix() {
+        let (_, file) = reversed(None);
+        let durable = file
+            .write_stream(0, Iter(chunks().into_iter()), 2)
+            .await
+            .unwrap();
+        assert_eq!(durable, 30);
+
+        let (_, file) = reversed(Some(10));
+        let err = file
+            .write_stream(0, Iter(chunks().into_iter()), 3)
+            .await
+            .unwrap_err();
+        assert_eq!(err.error().operation(), Operation::WriteAt);
+        // The write at 20 succeeded, but it is not contiguous with the first chunk.
+        assert_eq!(err.durable(), 10);
+    }
+}
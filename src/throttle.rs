@@ -0,0 +1,421 @@
+use crate::{BackendKind, File, FileIo, IoBuf, IoBufMut, Metadata, Native};
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    burst: Option<u64>,
+    tokens: f64,
+    last: Instant,
+    // Tokens refilled so far, without the cap. A waiter is done once this reaches the target it
+    // was given, which still holds when the rate changes while it waits.
+    paid: f64,
+    // Bumped when the limit is lifted, which settles every debt taken on before.
+    epoch: u64,
+}
+
+// What a request owes a bucket: it may go once `paid` reaches `target`, or the epoch moves on.
+#[derive(Debug, Clone, Copy)]
+struct Debt {
+    target: f64,
+    epoch: u64,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            rate: None,
+            burst: None,
+            tokens: 0.0,
+            last: Instant::now(),
+            paid: 0.0,
+            epoch: 0,
+        }
+    }
+
+    // Without an explicit burst the bucket holds one second worth of tokens.
+    fn capacity(&self) -> f64 {
+        match (self.burst, self.rate) {
+            (Some(burst), _) => burst as f64,
+            (None, Some(rate)) => rate as f64,
+            (None, None) => 0.0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let gained = now.saturating_duration_since(self.last).as_secs_f64() * rate as f64;
+            self.paid += gained;
+            self.tokens = (self.tokens + gained).min(self.capacity());
+        }
+        self.last = now;
+    }
+
+    fn reconfigure(&mut self, rate: Option<u64>, burst: Option<u64>) {
+        self.refill(Instant::now());
+        let was_limited = self.rate.is_some();
+        self.rate = rate.filter(|&rate| rate != 0);
+        self.burst = burst;
+        if !was_limited {
+            self.tokens = self.capacity();
+        }
+        if was_limited && self.rate.is_none() {
+            self.epoch += 1;
+            self.paid = 0.0;
+        }
+        self.tokens = self.tokens.min(self.capacity());
+    }
+
+    // Tokens may go negative: a request larger than the burst is let through once the debt it
+    // leaves behind has been paid off, and everyone after it queues behind that debt.
+    fn reserve(&mut self, cost: u64, now: Instant) -> Debt {
+        if self.rate.is_some() {
+            self.refill(now);
+            self.tokens -= cost as f64;
+        }
+        Debt {
+            target: self.paid - self.tokens.min(0.0),
+            epoch: self.epoch,
+        }
+    }
+
+    // How long until `debt` is paid off at the current rate.
+    fn delay(&mut self, debt: Debt, now: Instant) -> Duration {
+        let Some(rate) = self.rate.filter(|_| debt.epoch == self.epoch) else {
+            return Duration::ZERO;
+        };
+        self.refill(now);
+        if self.paid >= debt.target {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64((debt.target - self.paid) / rate as f64)
+                .unwrap_or(Duration::MAX)
+        }
+    }
+
+    fn refund(&mut self, cost: u64, now: Instant) {
+        if self.rate.is_some() {
+            self.refill(now);
+            self.tokens = (self.tokens + cost as f64).min(self.capacity());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    bytes: Bucket,
+    ops: Bucket,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    // Wakes waiters after a rate or burst change so they can work out their delay again.
+    reconfigured: Notify,
+}
+
+#[derive(Debug, Clone)]
+pub struct Throttle(Arc<Shared>);
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self(Arc::new(Shared {
+            state: Mutex::new(State {
+                bytes: Bucket::new(),
+                ops: Bucket::new(),
+            }),
+            reconfigured: Notify::new(),
+        }))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reconfigure(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state());
+        self.0.reconfigured.notify_waiters();
+    }
+
+    pub fn with_bytes_per_sec(self, rate: u64) -> Self {
+        self.set_bytes_per_sec(Some(rate));
+        self
+    }
+
+    pub fn with_ops_per_sec(self, rate: u64) -> Self {
+        self.set_ops_per_sec(Some(rate));
+        self
+    }
+
+    pub fn with_byte_burst(self, burst: u64) -> Self {
+        self.set_byte_burst(Some(burst));
+        self
+    }
+
+    pub fn with_op_burst(self, burst: u64) -> Self {
+        self.set_op_burst(Some(burst));
+        self
+    }
+
+    pub fn set_bytes_per_sec(&self, rate: Option<u64>) {
+        self.reconfigure(|state| {
+            let burst = state.bytes.burst;
+            state.bytes.reconfigure(rate, burst);
+        });
+    }
+
+    pub fn set_ops_per_sec(&self, rate: Option<u64>) {
+        self.reconfigure(|state| {
+            let burst = state.ops.burst;
+            state.ops.reconfigure(rate, burst);
+        });
+    }
+
+    pub fn set_byte_burst(&self, burst: Option<u64>) {
+        self.reconfigure(|state| {
+            let rate = state.bytes.rate;
+            state.bytes.reconfigure(rate, burst);
+        });
+    }
+
+    pub fn set_op_burst(&self, burst: Option<u64>) {
+        self.reconfigure(|state| {
+            let rate = state.ops.rate;
+            state.ops.reconfigure(rate, burst);
+        });
+    }
+
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.state().bytes.rate
+    }
+
+    pub fn ops_per_sec(&self) -> Option<u64> {
+        self.state().ops.rate
+    }
+
+    pub fn attach<B: FileIo>(&self, file: File<B>) -> File<ThrottledFile<B>> {
+        File::from_io(ThrottledFile::new(file, self.clone()))
+    }
+
+    // The delay is worked out again whenever the throttle is reconfigured, so lifting or raising
+    // the limit also frees requests that are already waiting.
+    pub async fn acquire(&self, bytes: u64) {
+        let (bytes_debt, ops_debt) = {
+            let mut state = self.state();
+            let now = Instant::now();
+            (state.bytes.reserve(bytes, now), state.ops.reserve(1, now))
+        };
+        let mut reservation = Reservation {
+            throttle: self,
+            bytes,
+            armed: true,
+        };
+        loop {
+            let reconfigured = self.0.reconfigured.notified();
+            let delay = {
+                let mut state = self.state();
+                let now = Instant::now();
+                state
+                    .bytes
+                    .delay(bytes_debt, now)
+                    .max(state.ops.delay(ops_debt, now))
+            };
+            if delay.is_zero() || tokio::time::timeout(delay, reconfigured).await.is_err() {
+                break;
+            }
+        }
+        reservation.armed = false;
+    }
+}
+
+// Gives the tokens back if `acquire` is dropped while waiting, so a cancelled request does not
+// keep delaying the ones queued behind it.
+struct Reservation<'a> {
+    throttle: &'a Throttle,
+    bytes: u64,
+    armed: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let mut state = self.throttle.state();
+            let now = Instant::now();
+            state.bytes.refund(self.bytes, now);
+            state.ops.refund(1, now);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ThrottledFile<B = Native> {
+    inner: B,
+    throttle: Throttle,
+}
+
+impl<B: FileIo> ThrottledFile<B> {
+    pub fn new(file: File<B>, throttle: Throttle) -> Self {
+        Self {
+            inner: file.into_inner(),
+            throttle,
+        }
+    }
+
+    pub fn into_file(self) -> File<Self> {
+        File::from_io(self)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+}
+
+impl<B: FileIo> FileIo for ThrottledFile<B> {
+    async fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.throttle.acquire(buf.len() as u64).await;
+        self.inner.read_at(pos, buf).await
+    }
+
+    async fn write_at(&self, pos: u64, buf: &[u8]) -> Result<usize> {
+        self.throttle.acquire(buf.len() as u64).await;
+        self.inner.write_at(pos, buf).await
+    }
+
+    async fn read_uninit_at(&self, pos: u64, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        self.throttle.acquire(buf.len() as u64).await;
+        self.inner.read_uninit_at(pos, buf).await
+    }
+
+    async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let len = buf.bytes_total() - buf.bytes_init();
+        self.throttle.acquire(len as u64).await;
+        self.inner.read_owned_at(pos, buf).await
+    }
+
+    async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        self.throttle.acquire(buf.bytes_init() as u64).await;
+        self.inner.write_owned_at(pos, buf).await
+    }
+
+    async fn sync_all(&self) -> Result<()> {
+        self.inner.sync_all().await
+    }
+
+    async fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data().await
+    }
+
+    async fn set_len(&self, size: u64) -> Result<()> {
+        self.inner.set_len(size).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        self.inner.metadata().await
+    }
+
+    fn kind(&self) -> BackendKind {
+        self.inner.kind()
+    }
+
+    fn descriptor(&self) -> Option<i64> {
+        self.inner.descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elapsed(start: Instant) -> Duration {
+        Instant::now() - start
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn byte_rate_is_enforced_after_the_burst() {
+        let throttle = Throttle::new().with_bytes_per_sec(1000);
+        let start = Instant::now();
+        throttle.acquire(1000).await;
+        assert_eq!(elapsed(start), Duration::ZERO);
+        throttle.acquire(500).await;
+        assert_eq!(elapsed(start), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_requests_go_into_debt() {
+        let throttle = Throttle::new().with_ops_per_sec(10).with_op_burst(1);
+        let start = Instant::now();
+        throttle.acquire(0).await;
+        throttle.acquire(0).await;
+        throttle.acquire(0).await;
+        assert_eq!(elapsed(start), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_acquire_refunds_its_tokens() {
+        let throttle = Throttle::new().with_bytes_per_sec(1000);
+        throttle.acquire(1000).await;
+        let cancelled = tokio::time::timeout(Duration::from_millis(1), throttle.acquire(10_000));
+        assert!(cancelled.await.is_err());
+        let start = Instant::now();
+        throttle.acquire(100).await;
+        assert!(elapsed(start) <= Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn raising_the_rate_shortens_a_pending_wait() {
+        let throttle = Throttle::new().with_bytes_per_sec(100);
+        throttle.acquire(100).await;
+        let start = Instant::now();
+        let waiter = tokio::spawn({
+            let throttle = throttle.clone();
+            async move { throttle.acquire(100).await }
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        throttle.set_bytes_per_sec(Some(1000));
+        waiter.await.unwrap();
+        let waited = elapsed(start);
+        assert!(waited >= Duration::from_millis(549) && waited <= Duration::from_millis(551));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removing_the_rate_releases_waiters() {
+        let throttle = Throttle::new().with_bytes_per_sec(1);
+        let waiter = tokio::spawn({
+            let throttle = throttle.clone();
+            async move { throttle.acquire(u64::MAX).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiter.is_finished());
+        throttle.set_bytes_per_sec(None);
+        let released = tokio::time::timeout(Duration::from_millis(1), waiter);
+        assert!(released.await.is_ok());
+        throttle.set_bytes_per_sec(Some(1000));
+        let start = Instant::now();
+        throttle.acquire(1500).await;
+        assert_eq!(elapsed(start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn huge_debt_does_not_panic() {
+        let mut bucket = Bucket::new();
+        bucket.reconfigure(Some(1), None);
+        let now = Instant::now();
+        let debt = bucket.reserve(u64::MAX, now);
+        assert_eq!(bucket.delay(debt, now), Duration::MAX);
+    }
+}
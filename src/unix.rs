@@ -1,6 +1,10 @@
+use crate::buf::{copy_to_uninit, Limit};
+use crate::{BackendKind, IoBuf, IoBufMut};
 use std::io::Result;
+use std::mem::MaybeUninit;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub struct File(tokio::fs::File);
@@ -17,6 +21,48 @@ unsafe impl Send for MutPtr {}
 
 unsafe impl Sync for MutPtr {}
 
+pub(crate) async fn asyncify<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    #[cfg(target_os = "linux")]
+    let f = {
+        let prio = crate::sched::current_ioprio();
+        move || crate::sched::with_thread_ioprio(prio, f)
+    };
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) => Err(std::io::Error::other(e)),
+    }
+}
+
+// The buffer stays in a slot shared with the blocking task, so it outlives a cancelled caller
+// and is handed back even when the task could not be joined.
+async fn with_owned<T, R, F>(buf: T, f: F) -> (Result<R>, T)
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> Result<R> + Send + 'static,
+{
+    let slot = Arc::new(Mutex::new(Some(buf)));
+    let shared = slot.clone();
+    let res = asyncify(move || {
+        let mut guard = shared.lock().unwrap_or_else(|e| e.into_inner());
+        match guard.as_mut() {
+            Some(buf) => f(buf),
+            None => Err(std::io::ErrorKind::Other.into()),
+        }
+    })
+    .await;
+    let buf = slot
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .expect("buffer is only taken here");
+    (res, buf)
+}
+
 impl File {
     pub(crate) async fn open_with_options(
         options: &tokio::fs::OpenOptions,
@@ -54,11 +100,7 @@ impl File {
         let fd = self.0.as_raw_fd();
         let ptr = Ptr(buf.as_ptr() as *const libc::c_void);
         let len = buf.len();
-        let ret = tokio::task::spawn_blocking(move || -> Result<usize> {
-            Self::write_at_sync(fd, pos, ptr, len)
-        })
-        .await
-        .unwrap();
+        let ret = asyncify(move || Self::write_at_sync(fd, pos, ptr, len)).await;
         self.anchor(buf);
         ret
     }
@@ -78,15 +120,47 @@ impl File {
         let fd = self.0.as_raw_fd();
         let ptr = MutPtr(buf.as_mut_ptr() as *mut libc::c_void);
         let len = buf.len();
-        let ret = tokio::task::spawn_blocking(move || -> Result<usize> {
-            Self::read_at_sync(fd, pos, ptr, len)
-        })
-        .await
-        .unwrap();
+        let ret = asyncify(move || Self::read_at_sync(fd, pos, ptr, len)).await;
         self.anchor(buf);
         ret
     }
 
+    // The blocking task may still be reading after the caller is gone, so it fills a buffer of
+    // its own that is copied out afterwards. That costs an allocation and a copy per call;
+    // `read_owned_at` avoids both by handing the caller's buffer over instead.
+    pub(crate) async fn read_uninit_at(
+        &self,
+        pos: u64,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> Result<usize> {
+        let owned = Limit::new(Vec::with_capacity(buf.len()), buf.len());
+        let (res, owned) = self.read_owned_at(pos, owned).await;
+        let data = owned.into_inner();
+        copy_to_uninit(buf, &data);
+        res
+    }
+
+    pub(crate) async fn write_owned_at<T: IoBuf>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let fd = self.0.as_raw_fd();
+        with_owned(buf, move |buf| {
+            let ptr = Ptr(buf.stable_ptr() as *const libc::c_void);
+            Self::write_at_sync(fd, pos, ptr, buf.bytes_init())
+        })
+        .await
+    }
+
+    pub(crate) async fn read_owned_at<T: IoBufMut>(&self, pos: u64, buf: T) -> (Result<usize>, T) {
+        let fd = self.0.as_raw_fd();
+        with_owned(buf, move |buf| {
+            let init = buf.bytes_init();
+            let ptr = MutPtr(unsafe { buf.stable_mut_ptr().add(init) } as *mut libc::c_void);
+            let cnt = Self::read_at_sync(fd, pos, ptr, buf.bytes_total() - init)?;
+            unsafe { buf.set_init(init + cnt) };
+            Ok(cnt)
+        })
+        .await
+    }
+
     pub async fn sync_all(&self) -> Result<()> {
         self.0.sync_all().await
     }
@@ -98,6 +172,22 @@ impl File {
     pub async fn set_len(&self, size: u64) -> Result<()> {
         self.0.set_len(size).await
     }
+
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        self.0.set_permissions(perm).await
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        Ok(Self(self.0.try_clone().await?))
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        BackendKind::ThreadPool
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        self.0
+    }
 }
 
 impl From<tokio::fs::File> for File {
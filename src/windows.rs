@@ -6,6 +6,7 @@ use winapi::um::fileapi::WriteFileEx;
 use winapi::um::minwinbase::OVERLAPPED;
 use winapi::um::winnt::HANDLE;
 
+use crate::BackendKind;
 use std::io::Result;
 use std::os::windows::io::{AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, RawHandle};
 use std::path::Path;
@@ -231,6 +232,22 @@ impl File {
     pub async fn set_len(&self, size: u64) -> Result<()> {
         self.0.set_len(size).await
     }
+
+    pub async fn set_permissions(&self, perm: std::fs::Permissions) -> Result<()> {
+        self.0.set_permissions(perm).await
+    }
+
+    pub async fn try_clone(&self) -> Result<Self> {
+        Ok(Self(self.0.try_clone().await?))
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        BackendKind::Overlapped
+    }
+
+    pub fn into_tokio(self) -> tokio::fs::File {
+        self.0
+    }
 }
 
 impl From<tokio::fs::File> for File {
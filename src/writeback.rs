@@ -0,0 +1,125 @@
+use crate::{Error, File, Operation, Result, SyncRangeFlags};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub struct WritebackScheduler {
+    tx: mpsc::UnboundedSender<Range<u64>>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl WritebackScheduler {
+    /// # Panics
+    ///
+    /// Spawns its worker with `tokio::spawn`, so it panics when called outside a Tokio runtime.
+    pub fn new(file: Arc<File>, chunk_size: u64) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(Self::run(file, chunk_size.max(1), rx));
+        Self { tx, task }
+    }
+
+    pub fn written(&self, offset: u64, len: u64) {
+        if len != 0 {
+            let _ = self.tx.send(offset..offset + len);
+        }
+    }
+
+    pub async fn finish(self) -> Result<()> {
+        drop(self.tx);
+        match self.task.await {
+            Ok(res) => res,
+            Err(e) => Err(Error::new(Operation::SyncRange, std::io::Error::other(e))),
+        }
+    }
+
+    async fn run(
+        file: Arc<File>,
+        chunk_size: u64,
+        mut rx: mpsc::UnboundedReceiver<Range<u64>>,
+    ) -> Result<()> {
+        let wait = SyncRangeFlags::WAIT_BEFORE | SyncRangeFlags::WRITE | SyncRangeFlags::WAIT_AFTER;
+        // Disjoint, non-adjacent dirty ranges keyed by start. Keeping them apart means two
+        // writes far from each other never turn into one huge sync_range over the gap.
+        let mut pending: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut pending_bytes = 0;
+        let mut in_flight: Vec<Range<u64>> = Vec::new();
+        while let Some(range) = rx.recv().await {
+            pending_bytes = insert(&mut pending, range, pending_bytes);
+            if pending_bytes < chunk_size {
+                continue;
+            }
+            let batch: Vec<Range<u64>> = std::mem::take(&mut pending)
+                .into_iter()
+                .map(|(start, end)| start..end)
+                .collect();
+            pending_bytes = 0;
+            // Start writeback of the newly dirtied chunk, then wait for the previous one so the
+            // amount of dirty data in the page cache stays bounded to roughly two chunks.
+            for range in &batch {
+                file.sync_range(range.start, range.end - range.start, SyncRangeFlags::WRITE)
+                    .await?;
+            }
+            for prev in std::mem::replace(&mut in_flight, batch) {
+                file.sync_range(prev.start, prev.end - prev.start, wait)
+                    .await?;
+            }
+        }
+        let rest = pending.into_iter().map(|(start, end)| start..end);
+        for range in in_flight.into_iter().chain(rest) {
+            file.sync_range(range.start, range.end - range.start, wait)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// Adds `range` to `pending`, merging it only with ranges it overlaps or touches, and returns the
+// new number of dirty bytes.
+fn insert(pending: &mut BTreeMap<u64, u64>, range: Range<u64>, mut bytes: u64) -> u64 {
+    let (mut start, mut end) = (range.start, range.end);
+    let touching: Vec<(u64, u64)> = pending
+        .range(..=end)
+        .rev()
+        .take_while(|(_, &e)| e >= start)
+        .map(|(&s, &e)| (s, e))
+        .collect();
+    for (s, e) in touching {
+        pending.remove(&s);
+        bytes -= e - s;
+        start = start.min(s);
+        end = end.max(e);
+    }
+    pending.insert(start, end);
+    bytes + (end - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn far_apart_ranges_stay_separate() {
+        let mut pending = BTreeMap::new();
+        let mut bytes = insert(&mut pending, 0..4096, 0);
+        bytes = insert(&mut pending, 10 << 30..(10 << 30) + 4096, bytes);
+        assert_eq!(bytes, 8192);
+        assert_eq!(
+            pending.into_iter().collect::<Vec<_>>(),
+            [(0, 4096), (10 << 30, (10 << 30) + 4096)]
+        );
+    }
+
+    #[test]
+    fn touching_and_overlapping_ranges_merge() {
+        let mut pending = BTreeMap::new();
+        let mut bytes = insert(&mut pending, 100..200, 0);
+        bytes = insert(&mut pending, 300..400, bytes);
+        bytes = insert(&mut pending, 200..250, bytes);
+        assert_eq!(bytes, 250);
+        bytes = insert(&mut pending, 240..310, bytes);
+        assert_eq!(bytes, 300);
+        assert_eq!(pending.into_iter().collect::<Vec<_>>(), [(100, 400)]);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::unix;
+use std::ffi::{CString, OsStr, OsString};
+use std::io::Result;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+fn c_name(name: &OsStr) -> Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+fn cvt(ret: libc::ssize_t) -> Result<usize> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+// Queries the required size first, then reads into a buffer of that size. The value can grow
+// between the two calls, in which case the kernel reports ERANGE and the query is repeated. An
+// empty value is returned as is: reading it with a zero-length buffer would only be another size
+// query, and would make a value that grew in the meantime look empty.
+fn read_sized(mut f: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t) -> Result<Vec<u8>> {
+    loop {
+        let size = cvt(f(std::ptr::null_mut(), 0))?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0u8; size];
+        match cvt(f(buf.as_mut_ptr() as *mut libc::c_void, buf.len())) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Each call duplicates the descriptor for its blocking task, which can outlive a dropped future
+// and the file with it; the raw number could by then belong to an unrelated file.
+pub(crate) async fn get(fd: BorrowedFd<'_>, name: &OsStr) -> Result<Vec<u8>> {
+    let fd = fd.try_clone_to_owned()?;
+    let name = c_name(name)?;
+    unix::asyncify(move || {
+        let fd = fd.as_raw_fd();
+        read_sized(|buf, len| unsafe { libc::fgetxattr(fd, name.as_ptr(), buf, len) })
+    })
+    .await
+}
+
+pub(crate) async fn set(fd: BorrowedFd<'_>, name: &OsStr, value: &[u8]) -> Result<()> {
+    let fd = fd.try_clone_to_owned()?;
+    let name = c_name(name)?;
+    let value = value.to_vec();
+    unix::asyncify(move || {
+        let ret = unsafe {
+            libc::fsetxattr(
+                fd.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        cvt(ret as libc::ssize_t).map(drop)
+    })
+    .await
+}
+
+pub(crate) async fn list(fd: BorrowedFd<'_>) -> Result<Vec<OsString>> {
+    let fd = fd.try_clone_to_owned()?;
+    let buf = unix::asyncify(move || {
+        let fd = fd.as_raw_fd();
+        read_sized(|buf, len| unsafe { libc::flistxattr(fd, buf as *mut libc::c_char, len) })
+    })
+    .await?;
+    Ok(buf
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsString::from_vec(name.to_vec()))
+        .collect())
+}
+
+pub(crate) async fn remove(fd: BorrowedFd<'_>, name: &OsStr) -> Result<()> {
+    let fd = fd.try_clone_to_owned()?;
+    let name = c_name(name)?;
+    unix::asyncify(move || {
+        cvt(unsafe { libc::fremovexattr(fd.as_raw_fd(), name.as_ptr()) } as libc::ssize_t).map(drop)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays the kernel's part for a value that changes between calls: a zero-length buffer asks
+    // for the size, and a buffer that is too small fails with ERANGE.
+    fn fake(
+        values: &'static [&'static [u8]],
+    ) -> impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t {
+        let mut calls = 0;
+        move |buf, len| {
+            let value = values[calls.min(values.len() - 1)];
+            calls += 1;
+            if len == 0 {
+                value.len() as libc::ssize_t
+            } else if len < value.len() {
+                unsafe { *libc::__errno_location() = libc::ERANGE };
+                -1
+            } else {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, value.len())
+                };
+                value.len() as libc::ssize_t
+            }
+        }
+    }
+
+    #[test]
+    fn empty_value_is_not_read_again() {
+        assert_eq!(read_sized(fake(&[b"", b"grown"])).unwrap(), b"");
+    }
+
+    #[test]
+    fn value_that_grows_is_queried_again() {
+        let values: &[&[u8]] = &[b"ab", b"abc", b"abc", b"abc"];
+        assert_eq!(read_sized(fake(values)).unwrap(), b"abc");
+    }
+}